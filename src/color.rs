@@ -0,0 +1,255 @@
+extern crate image;
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+use self::image::RgbImage;
+
+/// Shotwell's adjustment sliders range from -16 to 16.
+const SLIDER_RANGE: f64 = 16.0;
+/// Share by which the temperature and tint sliders scale the channels they act on at their ends,
+/// the `INTENSITY_FACTOR` of Shotwell's `TemperatureTransformation` and `TintTransformation`.
+const TINT_STRENGTH: f64 = 0.25;
+/// Largest shift of HSV value by the shadows and highlights sliders, reached in the middle of the
+/// dark or bright half of the range. Shotwell's `ShadowDetailTransformation` and
+/// `HighlightDetailTransformation` bend the tone curve with a spline; this sine bump follows
+/// their effect at mid-tones without reproducing the spline.
+const DETAIL_STRENGTH: f64 = 0.15;
+/// Gain added by the exposure and contrast sliders at their ends, a linear stand-in for the
+/// curves of Shotwell's `ExposureTransformation` and `ContrastTransformation`.
+const GAIN_STRENGTH: f64 = 0.5;
+
+/// Affine RGB transformation: each row maps `(r, g, b, 1)` to one output channel.
+type Matrix = [[f64; 4]; 3];
+
+/// Matrix applying `first`, then `second`.
+fn compose(first: &Matrix, second: &Matrix) -> Matrix {
+    let mut result = [[0.0; 4]; 3];
+    for (row, result_row) in result.iter_mut().enumerate() {
+        for (col, value) in result_row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| second[row][k] * first[k][col]).sum::<f64>();
+        }
+        result_row[3] += second[row][3];
+    }
+    result
+}
+
+fn scale(r: f64, g: f64, b: f64) -> Matrix {
+    [[r, 0.0, 0.0, 0.0],
+     [0.0, g, 0.0, 0.0],
+     [0.0, 0.0, b, 0.0]]
+}
+
+/// Colour adjustments from the `[adjustments]` group of `PhotoTable.transformations`.
+///
+/// Tonal adjustments (contrast expansion, shadows and highlights) remap the HSV value of each
+/// pixel, the others are combined into a single RGB matrix, following the split Shotwell's
+/// pixel transformer uses.
+#[derive(Debug, Default)]
+pub struct Adjustments {
+    expansion: Option<(u8, u8)>,
+    exposure: f64,
+    saturation: f64,
+    tint: f64,
+    temperature: f64,
+    shadows: f64,
+    highlights: f64,
+    contrast: f64,
+}
+
+impl Adjustments {
+    pub fn parse(group: Option<&HashMap<String, String>>) -> Adjustments {
+        let group = match group {
+            Some(group) => group,
+            None => return Adjustments::default(),
+        };
+        let slider = |key: &str| group.get(key)
+                                      .and_then(|v| v.parse::<f64>().ok())
+                                      .map(|v| (v / SLIDER_RANGE).clamp(-1.0, 1.0))
+                                      .unwrap_or(0.0);
+        let expansion = group.get("expansion").and_then(|value| {
+            let mut bounds = value.trim_matches(|c| c == '{' || c == '}').split(',').map(|v| v.trim().parse::<u8>());
+            match (bounds.next(), bounds.next()) {
+                (Some(Ok(black)), Some(Ok(white))) if black < white => Some((black, white)),
+                _ => None,
+            }
+        });
+        Adjustments {
+            expansion,
+            exposure: slider("exposure"),
+            saturation: slider("saturation"),
+            tint: slider("tint"),
+            temperature: slider("temperature"),
+            shadows: slider("shadows"),
+            highlights: slider("highlights"),
+            contrast: slider("contrast"),
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.expansion.is_none_or(|bounds| bounds == (0, 255))
+            && self.exposure == 0.0 && self.saturation == 0.0 && self.tint == 0.0 && self.temperature == 0.0
+            && self.shadows == 0.0 && self.highlights == 0.0 && self.contrast == 0.0
+    }
+
+    /// Remapping of HSV value (`max(r, g, b)`) for every 8-bit input level.
+    fn tone_curve(&self) -> [f64; 256] {
+        let mut curve = [0.0; 256];
+        for (level, value) in curve.iter_mut().enumerate() {
+            let mut v = level as f64 / 255.0;
+            if let Some((black, white)) = self.expansion {
+                v = (level as f64 - black as f64) / (white as f64 - black as f64);
+            }
+            v = v.clamp(0.0, 1.0);
+            if v < 0.5 {
+                v += DETAIL_STRENGTH * self.shadows * (PI * v / 0.5).sin();
+            } else {
+                v += DETAIL_STRENGTH * self.highlights * (PI * (v - 0.5) / 0.5).sin();
+            }
+            *value = v.clamp(0.0, 1.0) * 255.0;
+        }
+        curve
+    }
+
+    fn matrix(&self) -> Matrix {
+        let temperature = scale(1.0 + TINT_STRENGTH * self.temperature, 1.0, 1.0 - TINT_STRENGTH * self.temperature);
+        let tint = scale(1.0, 1.0 - TINT_STRENGTH * self.tint, 1.0);
+        let s = 1.0 + self.saturation;
+        let (lr, lg, lb) = ((1.0 - s) * 0.299, (1.0 - s) * 0.587, (1.0 - s) * 0.114);
+        let saturation = [[lr + s, lg, lb, 0.0],
+                          [lr, lg + s, lb, 0.0],
+                          [lr, lg, lb + s, 0.0]];
+        let gain = 1.0 + GAIN_STRENGTH * self.exposure;
+        let exposure = scale(gain, gain, gain);
+        let c = 1.0 + GAIN_STRENGTH * self.contrast;
+        let offset = 127.5 * (1.0 - c);
+        let contrast = [[c, 0.0, 0.0, offset],
+                        [0.0, c, 0.0, offset],
+                        [0.0, 0.0, c, offset]];
+        [tint, saturation, exposure, contrast].iter().fold(temperature, |acc, m| compose(&acc, m))
+    }
+
+    pub fn apply(&self, image: &mut RgbImage) {
+        if self.is_identity() {
+            return;
+        }
+        let curve = self.tone_curve();
+        let matrix = self.matrix();
+        for pixel in image.pixels_mut() {
+            let max = pixel[0].max(pixel[1]).max(pixel[2]);
+            let factor = if max == 0 { 0.0 } else { curve[max as usize] / max as f64 };
+            let rgb = [pixel[0] as f64 * factor, pixel[1] as f64 * factor, pixel[2] as f64 * factor];
+            for (channel, row) in matrix.iter().enumerate() {
+                let value = row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2] + row[3];
+                pixel[channel] = value.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+}
+
+/// Red-eye correction circle from the `[redeye]` group, in raw pixel coordinates.
+#[derive(Debug)]
+pub struct RedEye {
+    x: f64,
+    y: f64,
+    radius: f64,
+}
+
+impl RedEye {
+    /// Parse `num_points` circles stored as `center<N>=(x, y)` and `radius<N>=r`.
+    pub fn parse(group: Option<&HashMap<String, String>>) -> Vec<RedEye> {
+        let group = match group {
+            Some(group) => group,
+            None => return Vec::new(),
+        };
+        let count = group.get("num_points").and_then(|v| v.parse::<usize>().ok()).unwrap_or(0);
+        (0..count).filter_map(|i| {
+            let center = group.get(&format!("center{}", i))?;
+            let mut coords = center.trim_matches(|c| c == '(' || c == ')').split(',').map(|v| v.trim().parse::<f64>());
+            Some(RedEye {
+                x: coords.next()?.ok()?,
+                y: coords.next()?.ok()?,
+                radius: group.get(&format!("radius{}", i))?.parse::<f64>().ok()?,
+            })
+        }).collect()
+    }
+
+    /// Pull the red channel of red-dominated pixels inside the circle down to the green and
+    /// blue average.
+    pub fn apply(&self, image: &mut RgbImage) {
+        let left = (self.x - self.radius).floor().max(0.0) as u32;
+        let top = (self.y - self.radius).floor().max(0.0) as u32;
+        let right = ((self.x + self.radius).floor().max(-1.0) + 1.0) as u32;
+        let bottom = ((self.y + self.radius).floor().max(-1.0) + 1.0) as u32;
+        let (right, bottom) = (right.min(image.width()), bottom.min(image.height()));
+        for y in top..bottom {
+            for x in left..right {
+                let (dx, dy) = (x as f64 - self.x, y as f64 - self.y);
+                if dx * dx + dy * dy > self.radius * self.radius {
+                    continue;
+                }
+                let pixel = image.get_pixel_mut(x, y);
+                let mean = ((pixel[1] as u16 + pixel[2] as u16) / 2) as u8;
+                if pixel[0] > pixel[1] && pixel[0] > pixel[2] {
+                    pixel[0] = mean;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::image::{Rgb, RgbImage};
+    use super::{Adjustments, RedEye};
+
+    fn group(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries.iter().map(|&(key, value)| (key.to_owned(), value.to_owned())).collect()
+    }
+
+    fn gradient() -> RgbImage {
+        RgbImage::from_fn(16, 16, |x, y| Rgb([(x * 16) as u8, (y * 16) as u8, ((x + y) * 8) as u8]))
+    }
+
+    #[test]
+    fn identity() {
+        let neutral = group(&[("exposure", "0"), ("saturation", "0"), ("tint", "0"), ("temperature", "0"),
+                              ("shadows", "0"), ("highlights", "0"), ("contrast", "0"), ("expansion", "{0, 255}")]);
+        for adjustments in &[Adjustments::parse(None), Adjustments::parse(Some(&neutral))] {
+            assert!(adjustments.is_identity());
+            let mut image = gradient();
+            adjustments.apply(&mut image);
+            assert_eq!(image, gradient());
+        }
+    }
+
+    #[test]
+    fn exposure() {
+        let mut image = RgbImage::from_pixel(1, 1, Rgb([100, 50, 20]));
+        Adjustments::parse(Some(&group(&[("exposure", "16")]))).apply(&mut image);
+        assert_eq!(image.get_pixel(0, 0), &Rgb([150, 75, 30]));
+    }
+
+    #[test]
+    fn saturation() {
+        let mut image = RgbImage::from_pixel(1, 1, Rgb([200, 100, 50]));
+        Adjustments::parse(Some(&group(&[("saturation", "-16")]))).apply(&mut image);
+        assert_eq!(image.get_pixel(0, 0), &Rgb([124, 124, 124]));
+    }
+
+    #[test]
+    fn redeye() {
+        let mut image = RgbImage::from_pixel(10, 10, Rgb([200, 40, 60]));
+        image.put_pixel(6, 5, Rgb([40, 60, 200]));
+        let redeyes = RedEye::parse(Some(&group(&[("num_points", "1"), ("center0", "(5, 5)"), ("radius0", "2")])));
+        assert_eq!(redeyes.len(), 1);
+        redeyes[0].apply(&mut image);
+        assert_eq!(image.get_pixel(5, 5), &Rgb([50, 40, 60]));
+        assert_eq!(image.get_pixel(5, 7), &Rgb([50, 40, 60]));
+        assert_eq!(image.get_pixel(6, 5), &Rgb([40, 60, 200]));
+        assert_eq!(image.get_pixel(7, 7), &Rgb([200, 40, 60]));
+        assert_eq!(image.get_pixel(0, 0), &Rgb([200, 40, 60]));
+    }
+}
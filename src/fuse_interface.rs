@@ -3,29 +3,97 @@ extern crate fuse;
 extern crate time;
 extern crate libc;
 
-use std::path::Path;
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::fs::{self, File, OpenOptions};
+use std::io;
 use std::ffi::OsStr;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::time::UNIX_EPOCH;
 use self::time::Timespec;
-use self::libc::{ENOENT, EINVAL};
-use self::fuse::{Filesystem, ReplyEntry, ReplyAttr, FileAttr, FileType};
+use self::libc::{c_int, ENOENT, EINVAL, EIO, ENODATA, ERANGE, EPERM, EROFS, EEXIST, ENOTDIR, ENOTEMPTY, XATTR_CREATE, XATTR_REPLACE, O_ACCMODE, O_RDONLY, O_TRUNC};
+use self::fuse::{Filesystem, ReplyEntry, ReplyAttr, ReplyEmpty, ReplyOpen, ReplyXattr, ReplyCreate, ReplyWrite, FileAttr, FileType};
 
-use sqlite_ex::{TextField, UnsignedField};
+use sqlite_ex::{self, TextField, UnsignedField};
+use source_id::SourceId;
+use render::RenderCache;
+use thumbnails::ThumbnailCache;
+use search::SavedSearch;
+use metadata::{is_metadata_xattr, Metadata, XATTR_PREFIX};
+use trash::Trash;
+use import::{self as importer, MediaKind};
+use pool::Pool;
 
 const TTL: Timespec = Timespec { sec: 60, nsec: 0};
+const NO_TTL: Timespec = Timespec { sec: 0, nsec: 0};
 const NOTIME: Timespec = Timespec { sec: 1, nsec: 0};
 
 const ROOT: u64 = 1;
+// Fixed directories without numbered contents take small inodes next to ROOT, saving the high
+// bits for kinds of numbered entries.
+const FLAGGED: u64 = 2;
+const HIDDEN: u64 = 3;
+const UNDATED: u64 = 4;
+const LAST_IMPORT: u64 = 5;
+/// `import/`, where files are dropped to add them to the library in `writable` mode.
+const IMPORT_DROP: u64 = 6;
+/// Files being written into `import/`, numbered below the inode kind flags.
+const UPLOAD: u64 = 1 << 47;
 const PHOTO: u64 = 1 << 51;
 const VIDEO: u64 = 1 << 52;
 const TAG: u64 = 1 << 53;
 const EVENT: u64 = 1 << 54;
+const EDITED: u64 = 1 << 55;
+const RENDERED: u64 = 1 << 56;
+/// `thumbnails/` and, combined with other inodes, their mirrors under `thumbnails/128/`.
+const THUMBNAILS: u64 = 1 << 57;
+/// Selects the `thumbnails/360/` mirror instead of `thumbnails/128/`.
+const THUMB_360: u64 = 1 << 58;
+const MIRROR_MASK: u64 = THUMBNAILS | THUMB_360;
+const RATINGS: u64 = 1 << 59;
+/// `by-date/` and its year, month and day directories, encoded as `year << 9 | month << 5 | day`.
+const BY_DATE: u64 = 1 << 60;
+const YEAR_SHIFT: u64 = 9;
+const MONTH_SHIFT: u64 = 5;
+const SEARCH: u64 = 1 << 61;
+const PERSON: u64 = 1 << 62;
+/// `imports/` and, combined with an `import_id`, its import roll directories.
+const IMPORT: u64 = 1 << 63;
+/// Bits left for row ids below the inode kind flags.
+const ID_MASK: u64 = PHOTO - 1;
+
+/// Entries of a media directory in `media_dirs` mode, combined with the inode of the photo or video.
+const MEMBER_MASK: u64 = 0x7 << 48;
+const MEMBER_ORIGINAL: u64 = 1 << 48;
+const MEMBER_EDITED: u64 = 2 << 48;
+const MEMBER_THUMB_128: u64 = 3 << 48;
+const MEMBER_THUMB_360: u64 = 4 << 48;
+const MEMBER_METADATA: u64 = 5 << 48;
+
+/// `fuse_open_out` flag making the kernel pass reads through instead of serving them from its
+/// page cache, bounded by the file size it last saw.
+const FOPEN_DIRECT_IO: u32 = 1 << 0;
+
+/// Imports finishing within this many seconds of each other share an import roll.
+const IMPORT_ROLL_WINDOW: i64 = 60;
+
+const PHOTO_FLAG_HIDDEN: u64 = 0x01;
+const PHOTO_FLAG_FLAGGED: u64 = 0x10;
+const VIDEO_FLAG_FLAGGED: u64 = 0x04;
+/// `PhotoTable.file_format` of RAW photos.
+const PHOTO_FORMAT_RAW: u64 = 1;
+
+/// Photo columns with the edited backing file taking precedence over the original:
+/// `id, filename, timestamp, title, filesize`.
+const EDITED_QUERY: &str = "SELECT p.id, COALESCE(b.filepath, p.filename), p.timestamp, p.title, COALESCE(b.filesize, p.filesize) \
+                            FROM PhotoTable p LEFT JOIN BackingPhotoTable b ON b.id = p.editable_id";
 
 enum FileId {
-    FileKind(u64),
-    DirKind(u64),
-    VideoKind(u64),
+    File(u64),
+    Dir(u64),
 }
 
 macro_rules! dir_attr(
@@ -54,7 +122,33 @@ dir_attr!(ROOT_ATTR => ROOT,
           PHOTO_ATTR => PHOTO,
           VIDEO_ATTR => VIDEO,
           TAG_ATTR => TAG,
-          EVENT_ATTR => EVENT);
+          EVENT_ATTR => EVENT,
+          EDITED_ATTR => EDITED,
+          RENDERED_ATTR => RENDERED,
+          THUMBNAILS_ATTR => THUMBNAILS,
+          RATINGS_ATTR => RATINGS,
+          FLAGGED_ATTR => FLAGGED,
+          HIDDEN_ATTR => HIDDEN,
+          BY_DATE_ATTR => BY_DATE,
+          SEARCH_ATTR => SEARCH,
+          PERSON_ATTR => PERSON,
+          IMPORT_ATTR => IMPORT);
+
+/// Directories of `ratings/` with the filter selecting their media; the inode of each is
+/// `RATINGS` combined with its 1-based position.
+const RATING_DIRS: [(&str, &str); 11] = [
+    ("rejected", "rating < 0"),
+    ("unrated", "rating = 0"),
+    ("1", "rating = 1"),
+    ("2", "rating = 2"),
+    ("3", "rating = 3"),
+    ("4", "rating = 4"),
+    ("5", "rating = 5"),
+    ("1-and-up", "rating >= 1"),
+    ("2-and-up", "rating >= 2"),
+    ("3-and-up", "rating >= 3"),
+    ("4-and-up", "rating >= 4"),
+];
 
 fn make_dirattr(inode: u64, ts: Timespec) -> FileAttr {
     FileAttr {
@@ -94,14 +188,410 @@ fn make_fileattr(inode: u64, filesize: u64, ts: Timespec) -> FileAttr {
     }
 }
 
-pub struct ShotwellVFS {
+/// Attributes of a file served from `path` on disk, dated by its modification time.
+fn disk_fileattr(inode: u64, path: &Path) -> io::Result<FileAttr> {
+    let metadata = fs::metadata(path)?;
+    let mtime = metadata.modified().ok()
+                        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+                        .map(|mtime| time::Timespec{sec: mtime.as_secs() as i64, nsec: 0})
+                        .unwrap_or(NOTIME);
+    Ok(make_fileattr(inode, metadata.len(), mtime))
+}
+
+/// Turn the attributes of a media file into those of its directory in `media_dirs` mode.
+fn media_dirattr(attr: FileAttr) -> FileAttr {
+    FileAttr { kind: FileType::Directory, size: 0, ..attr }
+}
+
+fn file_extension(filename: &str) -> &str {
+    filename.rfind('.').map(|x| &filename[x+1..]).unwrap_or("")
+}
+
+/// Build the `(id) title.ext` name under which a photo or video is exposed,
+/// falling back to its timestamp when the title is empty.
+fn media_name(id: u64, extension: &str, timestamp: i64, title: &str) -> String {
+    if !title.is_empty() {
+        format!("({}) {}.{}", id, title, extension)
+    } else {
+        let timestamp = time::at(time::Timespec{sec: timestamp, nsec: 0});
+        let tm = timestamp.strftime("%Y-%m-%d %H:%M").unwrap();
+        format!("({}) {}.{}", id, tm, extension)
+    }
+}
+
+/// Last component of a hierarchical `/Parent/Child` tag name; flat names are returned as is.
+fn tag_title(name: &str) -> &str {
+    name.rsplit('/').next().unwrap_or(name)
+}
+
+/// Query selecting both photos and videos matching `filter`.
+///
+/// Columns are `id, filename, timestamp, title, filesize, kind, exposure_time, import_id`, where
+/// `kind` is 0 for photos and 1 for videos. Parameter `?1` is reserved for the caller, filter parameters start at `?2`.
+///
+/// Besides the columns both tables share, `filter` may use `kind`, the `source_id` as Shotwell
+/// stores it in tag lists, and booleans unifying table-specific state: `flagged` and `hidden`
+/// from the `flags` bits, `raw` for RAW photos, and `internal_changes` and `external_changes`
+/// for photos with transformations or an externally edited file.
+fn media_query(filter: &str) -> String {
+    format!("SELECT id, filename, timestamp, title, filesize, kind, exposure_time, import_id \
+             FROM (SELECT *, 0 AS kind, 'thumb' || printf('%016x', id) AS source_id, \
+                          flags & {1} != 0 AS hidden, flags & {2} != 0 AS flagged, file_format = {4} AS raw, \
+                          COALESCE(transformations, '') != '' AS internal_changes, editable_id != -1 AS external_changes \
+                   FROM PhotoTable) WHERE {0} \
+             UNION ALL \
+             SELECT id, filename, timestamp, title, filesize, kind, exposure_time, import_id \
+             FROM (SELECT *, 1 AS kind, 'video-' || printf('%016x', id) AS source_id, \
+                          0 AS hidden, flags & {3} != 0 AS flagged, 0 AS raw, \
+                          0 AS internal_changes, 0 AS external_changes \
+                   FROM VideoTable) WHERE {0}",
+            filter, PHOTO_FLAG_HIDDEN, PHOTO_FLAG_FLAGGED, VIDEO_FLAG_FLAGGED, PHOTO_FORMAT_RAW)
+}
+
+/// Query selecting the photos `rendered/` can decode, matching `filter`.
+///
+/// Columns are `id, source, timestamp, title, orientation, transformations`, where `source` is
+/// the file rendered from: the externally edited version, else the JPEG Shotwell developed from a
+/// RAW photo with its selected developer, else the original. RAW photos that were never developed
+/// have no source and are left out. `filter` may use the columns of `PhotoTable`.
+fn rendered_query(filter: &str) -> String {
+    format!("SELECT id, source, timestamp, title, orientation, transformations \
+             FROM (SELECT p.*, COALESCE(e.filepath, d.filepath, CASE WHEN p.file_format = {1} THEN NULL ELSE p.filename END) AS source \
+                   FROM PhotoTable p \
+                   LEFT JOIN BackingPhotoTable e ON e.id = p.editable_id \
+                   LEFT JOIN BackingPhotoTable d ON d.id = CASE p.developer WHEN 'CAMERA' THEN p.develop_camera_id \
+                                                                            WHEN 'EMBEDDED' THEN p.develop_embedded_id \
+                                                                            ELSE p.develop_shotwell_id END) \
+             WHERE source IS NOT NULL AND {0}",
+            filter, PHOTO_FORMAT_RAW)
+}
+
+/// Filter for `media_query` matching media taken on a local date `LIKE ?2`, formatted as `YYYY-MM-DD`.
+const DATED_FILTER: &str = "exposure_time > 0 AND strftime('%Y-%m-%d', exposure_time, 'unixepoch', 'localtime') LIKE ?2";
+const UNDATED_FILTER: &str = "COALESCE(exposure_time, 0) <= 0";
+/// Filter for `media_query` matching media imported in the roll with `import_id` `?2`.
+const IMPORT_FILTER: &str = "import_id = ?2";
+/// Filter for `media_query` matching photos in which the person with id `?2` was recognised.
+const PERSON_FILTER: &str = "kind = 0 AND id IN (SELECT photo_id FROM FaceLocationTable WHERE face_id = ?2)";
+
+/// Year, month and day of a `by-date/` directory inode, zero below its level.
+fn date_parts(inode: u64) -> (u64, u64, u64) {
+    let id = inode & ID_MASK;
+    (id >> YEAR_SHIFT, (id >> MONTH_SHIFT) & 0xf, id & 0x1f)
+}
+
+/// `LIKE` pattern for the local dates of media within a `by-date/` directory.
+fn date_pattern(inode: u64) -> String {
+    match date_parts(inode) {
+        (0, _, _) => "%".to_owned(),
+        (year, 0, _) => format!("{:04}-%", year),
+        (year, month, 0) => format!("{:04}-{:02}-%", year, month),
+        (year, month, day) => format!("{:04}-{:02}-{:02}", year, month, day),
+    }
+}
+
+/// Inode of the directory containing the `by-date/` directory `inode`.
+fn date_parent(inode: u64) -> u64 {
+    match date_parts(inode) {
+        (0, _, _) => ROOT,
+        (_, 0, _) => BY_DATE,
+        (year, _, 0) => BY_DATE | year << YEAR_SHIFT,
+        (year, month, _) => BY_DATE | year << YEAR_SHIFT | month << MONTH_SHIFT,
+    }
+}
+
+/// Photo or video an inode of `photos/`, `videos/`, `events/` or `tags/` refers to.
+fn inode_source(inode: u64) -> Option<SourceId> {
+    let id = inode & ID_MASK;
+    match inode & !ID_MASK {
+        _ if id & MEMBER_MASK != 0 => None,
+        PHOTO if id != 0 => Some(SourceId::Photo(id)),
+        VIDEO if id != 0 => Some(SourceId::Video(id)),
+        _ => None,
+    }
+}
+
+/// Thumbnail size served by a `thumbnails/` mirror.
+fn mirror_size(mirror: u64) -> u32 {
+    if mirror & THUMB_360 == THUMB_360 { 360 } else { 128 }
+}
+
+/// Read up to `size` bytes of `file` from `offset`, short only at the end of the file.
+fn read_file(file: &File, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; size];
+    let mut filled = 0;
+    while filled < size {
+        match file.read_at(&mut buf[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    buf.truncate(filled);
+    Ok(buf)
+}
+
+/// Errno to reply with for a failed file operation.
+fn io_errno(e: &io::Error) -> c_int {
+    e.raw_os_error().unwrap_or(EIO)
+}
+
+/// Reply to an extended attribute request with `value`, or with its size when `size` is 0.
+fn reply_xattr(reply: ReplyXattr, value: &[u8], size: u32) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
+/// Title for a directory created or renamed as `name`: the name without the `[id] ` prefix
+/// directories are listed with.
+fn dir_title(name: &OsStr) -> Result<&str, c_int> {
+    let name = name.to_str().ok_or(EINVAL)?;
+    let title = match name.find("] ") {
+        Some(end) if name.starts_with('[') && name[1..end].parse::<u64>().is_ok() => &name[end + 2..],
+        _ => name,
+    };
+    if title.is_empty() { Err(EINVAL) } else { Ok(title) }
+}
+
+/// Whether `inode` is `tags/` or one of the tag directories within.
+fn is_tag_dir(inode: u64) -> bool {
+    inode & !ID_MASK == TAG
+}
+
+/// How long the kernel may keep the entry for `inode`. Tag directories are created and renamed
+/// under plain titles but listed as `[id] title`, so their entries are looked up again on every
+/// use and a name that stopped resolving is dropped instead of lingering.
+fn entry_ttl(inode: u64) -> &'static Timespec {
+    if is_tag_dir(inode) { &NO_TTL } else { &TTL }
+}
+
+/// Whether `inode` is `events/` or one of the event directories within.
+fn is_event_dir(inode: u64) -> bool {
+    inode & !ID_MASK == EVENT
+}
+
+/// Column of `PhotoTable` and `VideoTable` behind an extended attribute that can be written.
+fn xattr_column(name: &OsStr) -> Option<&'static str> {
+    match name.to_str()?.strip_prefix(XATTR_PREFIX)? {
+        "rating" => Some("rating"),
+        "title" => Some("title"),
+        "comment" => Some("comment"),
+        _ => None,
+    }
+}
+
+/// Directory listing, optionally redirected into a `thumbnails/` mirror.
+///
+/// Mirrored listings carry the mirror bits in every inode, name each media file after its
+/// original with a `.jpg` suffix and skip media whose thumbnail is missing from the cache.
+/// Otherwise, in `media_dirs` mode media files are listed as directories named without extension,
+/// and in `symlinks` mode as symbolic links, as are the edited and rendered photos.
+struct Listing {
+    reply: fuse::ReplyDirectory,
+    mirror: u64,
+    thumbnails: Option<ThumbnailCache>,
+    media_dirs: bool,
+    symlinks: bool,
+}
+
+impl Listing {
+    fn new(reply: fuse::ReplyDirectory, media_dirs: bool, symlinks: bool) -> Self {
+        Listing {
+            reply,
+            mirror: 0,
+            thumbnails: None,
+            media_dirs,
+            symlinks,
+        }
+    }
+
+    fn mirrored(reply: fuse::ReplyDirectory, mirror: u64, thumbnails: ThumbnailCache) -> Self {
+        Listing {
+            reply,
+            mirror,
+            thumbnails: Some(thumbnails),
+            media_dirs: false,
+            symlinks: false,
+        }
+    }
+
+    /// Add an entry, returning `true` when the reply buffer is full.
+    fn add<T: AsRef<OsStr>>(&mut self, inode: u64, offset: i64, kind: FileType, name: T) -> bool {
+        let thumbnails = match self.thumbnails {
+            Some(ref thumbnails) => thumbnails,
+            None if self.media_dirs && kind == FileType::RegularFile && inode_source(inode).is_some() => {
+                let name = name.as_ref();
+                let stem = name.to_str().and_then(|name| name.rfind('.').map(|pos| &name[..pos]));
+                return self.reply.add(inode, offset, FileType::Directory, stem.map(OsStr::new).unwrap_or(name));
+            },
+            None if self.symlinks && kind == FileType::RegularFile && (inode_source(inode).is_some() || is_derived_file(inode)) => {
+                return self.reply.add(inode, offset, FileType::Symlink, name);
+            },
+            None => return self.reply.add(inode, offset, kind, name),
+        };
+        if kind == FileType::Directory {
+            return self.reply.add(inode | self.mirror, offset, kind, name);
+        }
+        match inode_source(inode) {
+            Some(source) if thumbnails.path(mirror_size(self.mirror), source).exists() => {
+                let mut name = name.as_ref().to_os_string();
+                name.push(".jpg");
+                self.reply.add(inode | self.mirror, offset, kind, name)
+            },
+            _ => false,
+        }
+    }
+
+    /// Add the `.` and `..` entries still due for a listing resumed at `offset`, returning the
+    /// offset of the next entry, or `None` when `offset` is invalid.
+    fn dots(&mut self, inode: u64, parent: u64, offset: i64) -> Option<i64> {
+        if offset < 0 {
+            return None;
+        }
+        if offset == 0 {
+            self.add(inode, 1, FileType::Directory, ".");
+        }
+        if offset <= 1 {
+            self.add(parent, 2, FileType::Directory, "..");
+        }
+        Some(offset.max(2))
+    }
+
+    fn ok(self) {
+        self.reply.ok()
+    }
+
+    fn error(self, errno: c_int) {
+        self.reply.error(errno)
+    }
+}
+
+/// Whether `inode` is a photo listed under `photos-edited/` or `rendered/`.
+fn is_derived_file(inode: u64) -> bool {
+    inode & ID_MASK != 0 && (inode & !ID_MASK == EDITED | PHOTO || inode & !ID_MASK == RENDERED | PHOTO)
+}
+
+/// Whether `inode` is a file being written into `import/`.
+fn is_upload(inode: u64) -> bool {
+    inode & !ID_MASK == 0 && inode & UPLOAD == UPLOAD
+}
+
+/// File dropped into `import/`, written to a hidden file in the library until it is released.
+struct Upload {
+    name: String,
+    kind: MediaKind,
+    path: PathBuf,
+    file: File,
+    /// Handle `create` gave the writer: the file is imported when this handle is released, not
+    /// when others opening it meanwhile close theirs.
+    fh: u64,
+}
+
+struct MediaEntry {
+    inode: u64,
+    name: String,
+    filesize: u64,
+    timestamp: Timespec,
+}
+
+#[derive(Clone)]
+pub struct Options {
+    /// Directory where photos rendered for `rendered/` are cached.
+    pub render_cache: PathBuf,
+    /// Shotwell's thumbnail cache served under `thumbnails/`.
+    pub thumbnails: PathBuf,
+    /// Show hidden photos only in `hidden/`, as Shotwell does by default.
+    pub hide_hidden: bool,
+    /// Expose each photo and video as a directory with its original, edited version,
+    /// thumbnails and metadata.
+    pub media_dirs: bool,
+    /// Expose each photo and video as a symbolic link to its original file.
+    pub symlinks: bool,
+    /// Allow changes to be written back to the database; it is opened read-only otherwise.
+    pub writable: bool,
+    /// Home trash directory receiving files deleted from `photos/` and `videos/`.
+    pub trash: PathBuf,
+    /// Library directory files dropped into `import/` are copied to.
+    pub library: PathBuf,
+    /// Number of worker threads, each with its own read-only database connection.
+    pub threads: usize,
+}
+
+/// State shared between the session thread and the workers.
+#[derive(Default)]
+struct Shared {
+    /// Files being dropped into `import/`.
+    uploads: Mutex<HashMap<u64, Upload>>,
+    /// Backing files opened by `open`, by file handle.
+    handles: Mutex<HashMap<u64, Arc<File>>>,
+    next_handle: AtomicU64,
+}
+
+/// View of the library through one database connection.
+///
+/// Workers each hold one over a read-only connection to answer queries and reads; changes are
+/// made through the one owned by the session, which is writable in `writable` mode.
+struct Library {
     conn: sqlite::Connection,
+    render_cache: RenderCache,
+    thumbnails: ThumbnailCache,
+    hide_hidden: bool,
+    media_dirs: bool,
+    symlinks: bool,
+    writable: bool,
+    trash: Trash,
+    library: PathBuf,
+    shared: Arc<Shared>,
+    next_upload: u64,
+    /// Import id of the current import roll with the time its last file was imported.
+    import_roll: Option<(i64, i64)>,
 }
 
-impl ShotwellVFS {
-    pub fn new<T: AsRef<Path>>(path: T) -> Self {
-        ShotwellVFS {
-            conn: sqlite::open(path).unwrap(),
+/// Milliseconds to wait for locks held by other connections, such as a worker reading while the
+/// session writes.
+const BUSY_TIMEOUT: usize = 5000;
+
+impl Library {
+    fn connect(path: &Path, options: &Options, shared: Arc<Shared>, read_only: bool) -> Self {
+        let mut conn = sqlite_ex::open_existing(path, !read_only).unwrap_or_else(|e| panic!("Cannot open database {:?}: {}", path, e));
+        conn.set_busy_timeout(BUSY_TIMEOUT).unwrap();
+        Library {
+            conn,
+            render_cache: RenderCache::new(options.render_cache.clone()),
+            thumbnails: ThumbnailCache::new(options.thumbnails.clone()),
+            hide_hidden: options.hide_hidden,
+            media_dirs: options.media_dirs,
+            symlinks: options.symlinks,
+            writable: options.writable,
+            trash: Trash::new(options.trash.clone()),
+            library: options.library.clone(),
+            shared,
+            next_upload: 1,
+            import_roll: None,
+        }
+    }
+
+    /// Restrict a `media_query` filter to media that are not hidden, unless hidden media are shown.
+    fn visible(&self, filter: &str) -> String {
+        if self.hide_hidden {
+            format!("({}) AND NOT hidden", filter)
+        } else {
+            filter.to_owned()
+        }
+    }
+
+    /// SQL condition on a `PhotoTable.flags` column excluding hidden photos when they are not shown.
+    fn visible_photo(&self, flags: &str) -> String {
+        if self.hide_hidden {
+            format!("{} & {} = 0", flags, PHOTO_FLAG_HIDDEN)
+        } else {
+            "1".to_owned()
         }
     }
 
@@ -109,392 +599,2408 @@ impl ShotwellVFS {
         let filename = filename.to_str();
         match filename {
             Some(x) => match x.chars().next() {
-                Some('[') => x.find(']').and_then(|end| x[1..end].parse::<u64>().map(|idx| FileId::DirKind(idx)).ok()),
-                Some('(') => x.find(')').and_then(|end| x[1..end].parse::<u64>().map(|idx| FileId::FileKind(idx)).ok()),
+                Some('[') => x.find(']').and_then(|end| x[1..end].parse::<u64>().map(FileId::Dir).ok()),
+                Some('(') => x.find(')').and_then(|end| x[1..end].parse::<u64>().map(FileId::File).ok()),
                 _ => None,
             },
             _ => None
         }
     }
 
-    fn getattr_tag(&mut self, inode: u64, mut reply: ReplyAttr) {
-        let mut statement = self.conn.prepare("SELECT time_created FROM TagTable WHERE id = ?").unwrap();
-        statement.bind(1, (inode & !TAG) as i64).unwrap();
-        if let Ok(sqlite::State::Row) = statement.next() {
-            let timestamp = time::Timespec{sec: statement.read::<i64>(0).unwrap(), nsec: 0};
-            reply.attr(&TTL, &make_dirattr(inode, timestamp));
-        } else {
-            reply.error(ENOENT);
-        }
+    fn has_table(&self, table: &str) -> bool {
+        let mut statement = self.conn.prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name = ?").unwrap();
+        statement.bind(1, table).unwrap();
+        matches!(statement.next(), Ok(sqlite::State::Row))
     }
 
-    fn getattr_photo(&mut self, inode: u64, mut reply: ReplyAttr) {
-        let mut statement = self.conn.prepare("SELECT exposure_time, filesize FROM PhotoTable WHERE id = ?").unwrap();
-        statement.bind(1, (inode & !PHOTO) as i64).unwrap();
-        if let Ok(sqlite::State::Row) = statement.next() {
-            let timestamp = time::Timespec{sec: statement.read::<i64>(0).unwrap(), nsec: 0};
-            let filesize = statement.read_u64(1).unwrap();
-            reply.attr(&TTL, &make_fileattr(inode, filesize, timestamp));
-        } else {
-            reply.error(ENOENT);
-        }
+    /// Whether the schema has the face recognition tables of newer Shotwell versions.
+    fn has_faces(&self) -> bool {
+        self.has_table("FaceTable") && self.has_table("FaceLocationTable")
     }
 
-    fn readdir_root(&mut self, mut reply: fuse::ReplyDirectory, offset: i64) {
-        if offset != 0 {
-            reply.error(ENOENT);
-        } else {
-            reply.add(ROOT, 0, FileType::Directory, ".");
-            reply.add(ROOT, 1, FileType::Directory, "..");
-            reply.add(PHOTO, 2, FileType::Directory, "photos");
-            reply.add(VIDEO, 3, FileType::Directory, "videos");
-            reply.add(TAG, 4, FileType::Directory, "tags");
-            reply.add(EVENT, 5, FileType::Directory, "events");
-            reply.ok()
+    fn tag_name(&self, tag_id: u64) -> Option<String> {
+        let mut statement = self.conn.prepare("SELECT name FROM TagTable WHERE id = ?").unwrap();
+        statement.bind(1, tag_id as i64).unwrap();
+        match statement.next() {
+            Ok(sqlite::State::Row) => statement.read_text(0),
+            _ => None,
         }
     }
 
-    fn readdir_events(&mut self, mut reply: fuse::ReplyDirectory, offset: i64) {
-        if offset < 0 {
-            reply.error(ENOENT);
-            return;
+    /// Inode of the directory containing the tag with full name `name`.
+    ///
+    /// Hierarchical tags are stored as `/Parent/Child`, so the parent is the name without the last
+    /// path component; top-level and flat tags live directly in `tags/`.
+    fn tag_parent(&self, name: &str) -> u64 {
+        match name.rfind('/') {
+            Some(pos) if pos > 0 => self.tag_id(&name[..pos]).map(|tag_id| tag_id | TAG).unwrap_or(TAG),
+            _ => TAG,
         }
-        if offset == 0 {
-            reply.add(EVENT, 0, FileType::Directory, ".");
-            reply.add(ROOT, 1, FileType::Directory, "..");
+    }
+
+    fn tag_id(&self, name: &str) -> Option<u64> {
+        let mut statement = self.conn.prepare("SELECT id FROM TagTable WHERE name = ?").unwrap();
+        statement.bind(1, name).unwrap();
+        match statement.next() {
+            Ok(sqlite::State::Row) => statement.read_u64(0).ok(),
+            _ => None,
         }
-        let mut idx = offset + 2;
+    }
 
-        let mut statement = self.conn.prepare("SELECT id, name, time_created FROM EventTable ORDER BY time_created ASC LIMIT ?, 100").unwrap();
-        statement.bind(1, offset).unwrap();
+    /// Direct children of the tag named `parent` as `(id, title)` pairs, or top-level tags for `None`.
+    fn subtags(&self, parent: Option<&str>) -> Vec<(u64, String)> {
+        let mut statement = match parent {
+            None => self.conn.prepare("SELECT id, name FROM TagTable WHERE INSTR(LTRIM(name, '/'), '/') = 0 ORDER BY LTRIM(name, '/') ASC").unwrap(),
+            Some(parent) => {
+                let mut statement = self.conn.prepare("SELECT id, name FROM TagTable WHERE SUBSTR(name, 1, LENGTH(?1) + 1) = ?1 || '/' AND INSTR(SUBSTR(name, LENGTH(?1) + 2), '/') = 0 ORDER BY name ASC").unwrap();
+                statement.bind(1, parent).unwrap();
+                statement
+            },
+        };
+        let mut tags = Vec::new();
         while let Ok(sqlite::State::Row) = statement.next() {
-            let event_id = statement.read_u64(0).unwrap();
-            let inode = event_id | EVENT;
-            let name = statement.read_text(1).unwrap_or(String::new());
-            if !name.is_empty() {
-                debug!("event id {} has utf name {:?}", event_id, name);
-                reply.add(inode, idx, FileType::Directory, format!("[{}] {}", event_id, name));
-                idx += 1;
-            } else {
-                let timestamp = time::at(time::Timespec{sec: statement.read::<i64>(2).unwrap(), nsec: 0});
-                let tm = timestamp.strftime("%Y-%m-%d %H:%M").unwrap();
-                debug!("event id {} event name is empty, using timestamp `{}`", event_id, tm);
-                reply.add(inode, idx, FileType::Directory, format!("[{}] {}", event_id, tm));
-                idx += 1;
+            let tag_id = statement.read_u64(0).unwrap();
+            let name = statement.read_text(1).unwrap_or_default();
+            let title = tag_title(&name);
+            if !title.is_empty() {
+                tags.push((tag_id, title.to_owned()));
             }
-        };
-        reply.ok();
+        }
+        tags
     }
 
-    fn readdir_tags(&mut self, mut reply: fuse::ReplyDirectory, offset: i64) {
-        if offset < 0 {
-            reply.error(ENOENT);
-            return;
+    /// Items tagged with `tag_id`, in the order Shotwell keeps them.
+    fn tag_sources(&self, tag_id: u64) -> Vec<SourceId> {
+        let mut statement = self.conn.prepare("SELECT photo_id_list FROM TagTable WHERE id = ?").unwrap();
+        statement.bind(1, tag_id as i64).unwrap();
+        match statement.next() {
+            Ok(sqlite::State::Row) => SourceId::parse_list(&statement.read_text(0).unwrap_or_default()),
+            _ => Vec::new(),
         }
-        if offset == 0 {
-            reply.add(EVENT, 0, FileType::Directory, ".");
-            reply.add(ROOT, 1, FileType::Directory, "..");
+    }
+
+    /// Replace the items tagged with `tag_id`, each followed by a comma as Shotwell writes them.
+    fn set_tag_sources(&self, tag_id: u64, sources: &[SourceId]) -> Result<(), c_int> {
+        let list: String = sources.iter().map(|source| format!("{},", source)).collect();
+        let mut statement = self.conn.prepare("UPDATE TagTable SET photo_id_list = ? WHERE id = ?").unwrap();
+        statement.bind(1, &list[..]).unwrap();
+        statement.bind(2, tag_id as i64).unwrap();
+        statement.next().map(|_| ()).map_err(|e| {
+            warn!("failed to update items of tag id {}: {}", tag_id, e);
+            EIO
+        })
+    }
+
+    fn photo_visible(&self, photo_id: u64) -> bool {
+        let mut statement = self.conn.prepare(format!("SELECT id FROM PhotoTable WHERE id = ? AND {}", self.visible_photo("flags"))).unwrap();
+        statement.bind(1, photo_id as i64).unwrap();
+        matches!(statement.next(), Ok(sqlite::State::Row))
+    }
+
+    /// Attributes of a photo or video entry as the mount presents it: a directory in `media_dirs`
+    /// mode, a symbolic link to the original in `symlinks` mode, the file itself otherwise.
+    fn media_attr(&self, attr: FileAttr) -> FileAttr {
+        if self.media_dirs {
+            media_dirattr(attr)
+        } else {
+            self.link_attr(attr)
         }
-        let mut idx = offset + 2;
+    }
 
-        let mut statement = self.conn.prepare("SELECT id, LTRIM(name, '/') as tname, time_created FROM TagTable WHERE INSTR(tname, '/') = 0 ORDER BY tname ASC LIMIT ?, 100").unwrap();
-        statement.bind(1, offset).unwrap();
-        while let Ok(sqlite::State::Row) = statement.next() {
-            let tag_id = statement.read_u64(0).unwrap();
-            let inode = tag_id | EVENT;
-            let name = statement.read_text(1).unwrap_or(String::new());
-            if !name.is_empty() {
-                let mut title = &name[..];
-                if title.starts_with('/') {
-                    title = &title[1..];
-                }
-                debug!("tag id {} has utf name {:?}", tag_id, title);
-                reply.add(inode, idx, FileType::Directory, format!("[{}] {}", tag_id, title));
-                idx += 1;
-            }
+    /// Attributes of a photo, video, edited or rendered photo entry as a symbolic link to the file
+    /// backing it in `symlinks` mode, the file itself otherwise. Photos are not rendered for this:
+    /// the link points at where the rendering is cached and `readlink` renders it.
+    fn link_attr(&self, attr: FileAttr) -> FileAttr {
+        if !self.symlinks {
+            return attr;
+        }
+        let target = if attr.ino & RENDERED == RENDERED {
+            self.rendered_file(attr.ino & !(RENDERED | PHOTO), false)
+        } else {
+            self.backing_file(attr.ino)
         };
-        reply.ok();
+        let size = target.map(|path| path.as_os_str().len() as u64).unwrap_or(0);
+        FileAttr { kind: FileType::Symlink, size, perm: 0o777, ..attr }
     }
 
-    fn readdir_tag_contents(&mut self, inode: u64, mut reply: fuse::ReplyDirectory, offset: i64) {
-        if offset < 0 {
-            reply.error(ENOENT);
-            return;
-        }
-        if offset == 0 {
-            reply.add(TAG, 0, FileType::Directory, ".");
-            reply.add(ROOT, 1, FileType::Directory, "..");
+    /// Whether `name` refers to the media file listed as `listed`, or to its directory, which is
+    /// named without the extension in `media_dirs` mode.
+    fn is_media_name(&self, name: &OsStr, listed: &str) -> bool {
+        match name.to_str() {
+            Some(name) if name == listed => true,
+            Some(name) => self.media_dirs && listed.rfind('.').map(|pos| &listed[..pos]) == Some(name),
+            None => false,
         }
-        let mut idx = offset + 2;
+    }
 
-        // FIXME we need to list subtags too
-        let mut statement = self.conn.prepare("SELECT photo_id_list FROM TagTable WHERE id = ?").unwrap();
-        debug!("readdir for tag id {}", (inode & !TAG) as i64);
-        statement.bind(1, (inode & !TAG) as i64).unwrap();
+    /// Entries of the directory of media `inode` in `media_dirs` mode as `(inode, name)` pairs.
+    fn media_members(&self, inode: u64) -> Vec<(u64, String)> {
+        let source = match inode_source(inode) {
+            Some(source) => source,
+            None => return Vec::new(),
+        };
+        let (query, id) = match source {
+            SourceId::Photo(id) => ("SELECT p.filename, b.filepath FROM PhotoTable p LEFT JOIN BackingPhotoTable b ON b.id = p.editable_id WHERE p.id = ?", id),
+            SourceId::Video(id) => ("SELECT filename, NULL FROM VideoTable WHERE id = ?", id),
+        };
+        let mut statement = self.conn.prepare(query).unwrap();
+        statement.bind(1, id as i64).unwrap();
+        let mut members = Vec::new();
         if let Ok(sqlite::State::Row) = statement.next() {
-            for photo_id in statement.read_text(0).unwrap_or(String::new()).split(',').map(|id| id.parse::<u64>()).filter(|id| id.is_ok()).map(|id| id.unwrap()).skip(offset as usize).take(100) {
-                debug!("checking photo id {}", photo_id);
-                let mut statement2 = self.conn.prepare("SELECT filename, timestamp, title FROM PhotoTable WHERE id = ?").unwrap();
-                statement2.bind(1, photo_id as i64).unwrap();
-                if let Ok(sqlite::State::Row) = statement2.next() {
-                    let inode = photo_id | PHOTO;
-                    let filename = statement2.read_text(0).unwrap_or(String::new());
-                    let extension = filename.rfind('.').map(|x| &filename[x+1..]).unwrap_or("");
-                    let title = statement2.read_text(2).unwrap_or(String::new());
-                    if !title.is_empty() {
-                        debug!("photo id {} has utf name {:?}", photo_id, title);
-                        reply.add(inode, idx, FileType::RegularFile, format!("({}) {}.{}", photo_id, title, extension));
-                        idx += 1;
-                    } else {
-                        let timestamp = time::at(time::Timespec{sec: statement2.read::<i64>(1).unwrap(), nsec: 0});
-                        let tm = timestamp.strftime("%Y-%m-%d %H:%M").unwrap();
-                        debug!("photo id {} title is empty, using timestamp `{}`", photo_id, tm);
-                        reply.add(inode, idx, FileType::RegularFile, format!("({}) {}.{}", photo_id, tm, extension));
-                        idx += 1;
-                    }
+            let filename = statement.read_text(0).unwrap_or_default();
+            members.push((inode | MEMBER_ORIGINAL, format!("original.{}", file_extension(&filename))));
+            if let Some(edited) = statement.read_text(1).filter(|edited| !edited.is_empty()) {
+                members.push((inode | MEMBER_EDITED, format!("edited.{}", file_extension(&edited))));
+            }
+            for &(member, size) in &[(MEMBER_THUMB_128, 128), (MEMBER_THUMB_360, 360)] {
+                if self.thumbnails.path(size, source).exists() {
+                    members.push((inode | member, format!("thumb-{}.jpg", size)));
                 }
             }
+            members.push((inode | MEMBER_METADATA, "metadata.json".to_owned()));
         }
-        reply.ok();
+        members
     }
 
-    fn readdir_photos(&mut self, mut reply: fuse::ReplyDirectory, offset: i64) {
-        if offset < 0 {
-            reply.error(ENOENT);
-            return;
+    /// Path of the file served for an entry of a media directory; `metadata.json` has none.
+    fn member_file(&self, inode: u64) -> Result<PathBuf, c_int> {
+        let media = inode & !MEMBER_MASK;
+        let source = inode_source(media).ok_or(ENOENT)?;
+        match inode & MEMBER_MASK {
+            MEMBER_ORIGINAL => self.backing_file(media),
+            MEMBER_EDITED => self.backing_file(EDITED | media),
+            MEMBER_THUMB_128 => Ok(self.thumbnails.path(128, source)),
+            MEMBER_THUMB_360 => Ok(self.thumbnails.path(360, source)),
+            _ => Err(ENOENT),
         }
-        if offset == 0 {
-            reply.add(PHOTO, 0, FileType::Directory, ".");
-            reply.add(ROOT, 1, FileType::Directory, "..");
+    }
+
+    fn member_metadata(&self, inode: u64) -> Result<String, c_int> {
+        let source = inode_source(inode & !MEMBER_MASK).ok_or(ENOENT)?;
+        Metadata::load(&self.conn, source).map(|metadata| metadata.to_json()).ok_or(ENOENT)
+    }
+
+    /// Photo or video whose metadata `inode` carries as extended attributes: that of the originals,
+    /// edited versions, renderings, thumbnails and directory entries of photos and videos.
+    fn xattr_source(inode: u64) -> Option<SourceId> {
+        inode_source(inode & !(MIRROR_MASK | EDITED | RENDERED | MEMBER_MASK))
+    }
+
+    /// Extended attributes of `inode`, nothing for inodes that are not photos or videos.
+    fn xattrs(&self, inode: u64) -> Result<Vec<(String, String)>, c_int> {
+        match Library::xattr_source(inode) {
+            Some(source) => Metadata::load(&self.conn, source).map(|metadata| metadata.xattrs()).ok_or(ENOENT),
+            None => Ok(Vec::new()),
         }
-        let mut idx = offset + 2;
-        let mut statement = self.conn.prepare("SELECT id, filename, timestamp, title FROM PhotoTable ORDER BY timestamp ASC, id ASC LIMIT ?, 100").unwrap();
-        statement.bind(1, offset).unwrap();
-        while let Ok(sqlite::State::Row) = statement.next() {
-            let photo_id = statement.read_u64(0).unwrap();
-            let inode = photo_id | PHOTO;
-            let filename = statement.read_text(1).unwrap_or(String::new());
-            let extension = filename.rfind('.').map(|x| &filename[x+1..]).unwrap_or("");
-            let title = statement.read_text(3).unwrap_or(String::new());
-            if !title.is_empty() {
-                debug!("photo id {} has utf name {:?}", photo_id, title);
-                reply.add(inode, idx, FileType::RegularFile, format!("({}) {}.{}", photo_id, title, extension));
-                idx += 1;
-            } else {
-                let timestamp = time::at(time::Timespec{sec: statement.read::<i64>(2).unwrap(), nsec: 0});
-                let tm = timestamp.strftime("%Y-%m-%d %H:%M").unwrap();
-                debug!("photo id {} title is empty, using timestamp `{}`", photo_id, tm);
-                reply.add(inode, idx, FileType::RegularFile, format!("({}) {}.{}", photo_id, tm, extension));
-                idx += 1;
-            }
+    }
+
+    fn has_xattr(&self, inode: u64, name: &OsStr) -> Result<bool, c_int> {
+        Ok(self.xattrs(inode)?.iter().any(|(xattr, _)| name == OsStr::new(xattr)))
+    }
+
+    /// Set `column` of the row of `source` in `PhotoTable` or `VideoTable`.
+    fn update_media(&self, source: SourceId, column: &str, value: &sqlite::Value) -> Result<(), c_int> {
+        let (table, id) = match source {
+            SourceId::Photo(id) => ("PhotoTable", id),
+            SourceId::Video(id) => ("VideoTable", id),
         };
-        reply.ok();
+        let mut statement = self.conn.prepare(format!("UPDATE {} SET {} = ? WHERE id = ?", table, column)).unwrap();
+        statement.bind(1, value).unwrap();
+        statement.bind(2, id as i64).unwrap();
+        statement.next().map(|_| ()).map_err(|e| {
+            warn!("failed to update {} of {}: {}", column, source, e);
+            EIO
+        })
     }
 
-    fn readdir_videos(&mut self, mut reply: fuse::ReplyDirectory, offset: i64) {
-        if offset < 0 {
-            reply.error(ENOENT);
-            return;
+    /// Write a rating, title or comment given as an extended attribute. Ratings range from -1
+    /// (rejected) to 5; an empty title or comment is stored as `NULL`, as Shotwell does.
+    fn set_xattr(&mut self, inode: u64, name: &OsStr, value: &[u8], flags: u32) -> Result<(), c_int> {
+        if !self.writable {
+            return Err(EROFS);
         }
-        if offset == 0 {
-            reply.add(VIDEO, 0, FileType::Directory, ".");
-            reply.add(ROOT, 1, FileType::Directory, "..");
+        let source = Library::xattr_source(inode).ok_or(EPERM)?;
+        let column = xattr_column(name).ok_or(EPERM)?;
+        let exists = self.has_xattr(inode, name)?;
+        if flags & XATTR_CREATE as u32 != 0 && exists {
+            return Err(EEXIST);
         }
-        let mut idx = offset + 2;
-        let mut statement = self.conn.prepare("SELECT id, filename, timestamp, title FROM VideoTable ORDER BY timestamp ASC LIMIT ?, 100").unwrap();
-        statement.bind(1, offset).unwrap();
-        while let Ok(sqlite::State::Row) = statement.next() {
-            let video_id = statement.read_u64(0).unwrap();
-            let inode = video_id | VIDEO;
-            let filename = statement.read_text(1).unwrap_or(String::new());
-            let extension = filename.rfind('.').map(|x| &filename[x+1..]).unwrap_or("");
-            let title = statement.read_text(3).unwrap_or(String::new());
-            if !title.is_empty() {
-                debug!("video id {} has utf name {:?}", video_id, title);
-                reply.add(inode, idx, FileType::RegularFile, format!("({}) {}.{}", video_id, title, extension));
-                idx += 1;
-            } else {
-                let timestamp = time::at(time::Timespec{sec: statement.read::<i64>(2).unwrap(), nsec: 0});
-                let tm = timestamp.strftime("%Y-%m-%d %H:%M").unwrap();
-                debug!("video id {} title is empty, using timestamp `{}`", video_id, tm);
-                reply.add(inode, idx, FileType::RegularFile, format!("({}) {}.{}", video_id, tm, extension));
-                idx += 1;
-            }
+        if flags & XATTR_REPLACE as u32 != 0 && !exists {
+            return Err(ENODATA);
+        }
+        let text = std::str::from_utf8(value).map_err(|_| EINVAL)?;
+        let value = match column {
+            "rating" => match text.trim().parse::<i64>() {
+                Ok(rating) if (-1..=5).contains(&rating) => sqlite::Value::Integer(rating),
+                _ => return Err(EINVAL),
+            },
+            _ if text.is_empty() => sqlite::Value::Null,
+            _ => sqlite::Value::String(text.to_owned()),
         };
-        reply.ok();
+        self.update_media(source, column, &value)
     }
 
-    fn lookup_root(&mut self, name: &OsStr, reply: ReplyEntry) {
-        match name.to_str() {
-            Some("/") => reply.entry(&TTL, &ROOT_ATTR, 0),
-            Some("photos") => reply.entry(&TTL, &PHOTO_ATTR, 0),
-            Some("videos") => reply.entry(&TTL, &VIDEO_ATTR, 0),
-            Some("tags") => reply.entry(&TTL, &TAG_ATTR, 0),
-            Some("events") => reply.entry(&TTL, &EVENT_ATTR, 0),
-            _ => reply.error(ENOENT),
+    /// Clear a rating, title or comment: the photo or video becomes unrated or untitled.
+    fn remove_xattr(&mut self, inode: u64, name: &OsStr) -> Result<(), c_int> {
+        if !self.writable {
+            return Err(EROFS);
         }
+        let source = Library::xattr_source(inode).ok_or(EPERM)?;
+        let column = xattr_column(name).ok_or(EPERM)?;
+        if !self.has_xattr(inode, name)? {
+            return Err(ENODATA);
+        }
+        let value = if column == "rating" { sqlite::Value::Integer(0) } else { sqlite::Value::Null };
+        self.update_media(source, column, &value)
     }
 
-    fn lookup_event(&mut self, name: &OsStr, reply: ReplyEntry) {
-        if let Some(FileId::DirKind(id)) = self.extract_id(name) {
-            let mut statement = self.conn.prepare("SELECT time_created FROM EventTable WHERE id = ?").unwrap();
-            statement.bind(1, id as i64).unwrap();
-            if let Ok(sqlite::State::Row) = statement.next() {
-                let timestamp = time::Timespec{sec: statement.read::<i64>(0).unwrap(), nsec: 0};
-                reply.entry(&TTL, &make_dirattr(EVENT | id, timestamp), 0);
-                return;
-            }
+    /// Tag the photo or video `inode` with the tag directory `parent`, returning the attributes
+    /// of its new entry. The link must be named as the item is listed in tag directories.
+    fn link_entry(&mut self, inode: u64, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        if !self.writable {
+            return Err(EROFS);
+        }
+        let source = inode_source(inode).ok_or(EPERM)?;
+        if !is_tag_dir(parent) || parent == TAG {
+            return Err(EPERM);
         }
-        reply.error(ENOENT);
+        let entry = self.media_entry(source).ok_or(ENOENT)?;
+        if name != OsStr::new(&entry.name) {
+            return Err(EINVAL);
+        }
+        let tag_id = parent & ID_MASK;
+        self.tag_name(tag_id).ok_or(ENOENT)?;
+        let mut sources = self.tag_sources(tag_id);
+        if sources.contains(&source) {
+            return Err(EEXIST);
+        }
+        sources.push(source);
+        self.set_tag_sources(tag_id, &sources)?;
+        let attr = make_fileattr(entry.inode, entry.filesize, entry.timestamp);
+        Ok(self.media_attr(attr))
     }
 
-    fn lookup_tag(&mut self, name: &OsStr, reply: ReplyEntry) {
-        match self.extract_id(name) {
-            Some(FileId::DirKind(id)) => {
-                let mut statement = self.conn.prepare("SELECT time_created FROM TagTable WHERE id = ?").unwrap();
-                statement.bind(1, id as i64).unwrap();
-                if let Ok(sqlite::State::Row) = statement.next() {
-                    let timestamp = time::Timespec{sec: statement.read::<i64>(0).unwrap(), nsec: 0};
-                    reply.entry(&TTL, &make_dirattr(TAG | id, timestamp), 0);
-                }
-            },
-            Some(FileId::FileKind(id)) => {
-                let mut statement = self.conn.prepare("SELECT filesize, timestamp FROM PhotoTable WHERE id = ?").unwrap();
-                statement.bind(1, id as i64).unwrap();
-                if let Ok(sqlite::State::Row) = statement.next() {
-                    let timestamp = time::Timespec{sec: statement.read::<i64>(1).unwrap(), nsec: 0};
-                    let filesize = statement.read_u64(0).unwrap();
-                    reply.entry(&TTL, &make_fileattr(PHOTO | id, filesize, timestamp), 0);
-                }
+    /// Remove the entry `name` of directory `parent`. Within a tag directory this untags the photo
+    /// or video, leaving its other tags alone; in `photos/` and `videos/` it deletes it.
+    fn unlink_entry(&mut self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        if !self.writable {
+            return Err(EROFS);
+        }
+        match parent {
+            x if is_tag_dir(x) && x != TAG => {
+                let attr = self.lookup_tag(x, name)?;
+                let source = inode_source(attr.ino).ok_or(EPERM)?;
+                let mut sources = self.tag_sources(x & ID_MASK);
+                sources.retain(|&tagged| tagged != source);
+                self.set_tag_sources(x & ID_MASK, &sources)
             },
-            _ => reply.error(ENOENT),
-        };
+            PHOTO | VIDEO => self.delete_media(parent, name),
+            _ => Err(EPERM),
+        }
     }
 
-    fn lookup_photo(&mut self, name: &OsStr, reply: ReplyEntry) {
-        if let Some(FileId::FileKind(id)) = self.extract_id(name) {
-            let mut statement = self.conn.prepare("SELECT filesize, timestamp FROM PhotoTable WHERE id = ?").unwrap();
-            statement.bind(1, id as i64).unwrap();
-            if let Ok(sqlite::State::Row) = statement.next() {
-                let timestamp = time::Timespec{sec: statement.read::<i64>(1).unwrap(), nsec: 0};
-                let filesize = statement.read_u64(0).unwrap();
-                reply.entry(&TTL, &make_fileattr(PHOTO | id, filesize, timestamp), 0);
-                return;
-            }
+    /// Run `f` in a transaction, committed when it succeeds and rolled back otherwise.
+    fn transaction<T, F: FnOnce(&Self) -> Result<T, c_int>>(&self, f: F) -> Result<T, c_int> {
+        if let Err(e) = self.conn.execute("BEGIN") {
+            warn!("failed to begin a transaction: {}", e);
+            return Err(EIO);
         }
-        reply.error(ENOENT);
+        let result = f(self).and_then(|value| match self.conn.execute("COMMIT") {
+            Ok(()) => Ok(value),
+            Err(e) => {
+                warn!("failed to commit a transaction: {}", e);
+                Err(EIO)
+            },
+        });
+        if result.is_err() {
+            let _ = self.conn.execute("ROLLBACK");
+        }
+        result
     }
 
-    fn lookup_video(&mut self, name: &OsStr, reply: ReplyEntry) {
-        if let Some(FileId::FileKind(id)) = self.extract_id(name) {
-            let mut statement = self.conn.prepare("SELECT filesize, timestamp FROM VideoTable WHERE id = ?").unwrap();
-            statement.bind(1, id as i64).unwrap();
-            if let Ok(sqlite::State::Row) = statement.next() {
-                let timestamp = time::Timespec{sec: statement.read::<i64>(1).unwrap(), nsec: 0};
-                let filesize = statement.read_u64(0).unwrap();
-                reply.entry(&TTL, &make_fileattr(VIDEO | id, filesize, timestamp), 0);
-                return;
-            }
+    /// Full name of a tag titled `title` within the tag directory `parent`.
+    ///
+    /// Tags within another tag are hierarchical, so a flat parent is renamed to a top-level
+    /// hierarchical tag first; callers run this in the transaction adding the child, so the
+    /// parent stays flat if that fails. Top-level tags are flat unless `hierarchical` is set.
+    fn child_tag_name(&self, parent: u64, title: &str, hierarchical: bool) -> Result<String, c_int> {
+        if parent == TAG {
+            return Ok(if hierarchical { format!("/{}", title) } else { title.to_owned() });
         }
-        reply.error(ENOENT);
-
+        let parent_id = parent & ID_MASK;
+        let mut parent_name = self.tag_name(parent_id).ok_or(ENOENT)?;
+        if !parent_name.starts_with('/') {
+            let promoted = format!("/{}", parent_name);
+            self.rename_tag(parent_id, &parent_name, &promoted)?;
+            parent_name = promoted;
+        }
+        Ok(format!("{}/{}", parent_name, title))
     }
-}
 
-impl Filesystem for ShotwellVFS {
+    /// Rename tag `tag_id` from `old` to `new` along with the names of its subtags.
+    fn rename_tag(&self, tag_id: u64, old: &str, new: &str) -> Result<(), c_int> {
+        if self.tag_id(new).is_some() {
+            return Err(EEXIST);
+        }
+        let mut statement = self.conn.prepare("UPDATE TagTable SET name = ?1 || SUBSTR(name, LENGTH(?2) + 1) \
+                                               WHERE id = ?3 OR SUBSTR(name, 1, LENGTH(?2) + 1) = ?2 || '/'").unwrap();
+        statement.bind(1, new).unwrap();
+        statement.bind(2, old).unwrap();
+        statement.bind(3, tag_id as i64).unwrap();
+        statement.next().map(|_| ()).map_err(|e| {
+            warn!("failed to rename tag {:?} to {:?}: {}", old, new, e);
+            EIO
+        })
+    }
+
+    fn create_tag(&mut self, parent: u64, title: &str) -> Result<FileAttr, c_int> {
+        let now = time::get_time().sec;
+        let tag_id = self.transaction(|library| {
+            let name = library.child_tag_name(parent, title, false)?;
+            if library.tag_id(&name).is_some() {
+                return Err(EEXIST);
+            }
+            let mut statement = library.conn.prepare("INSERT INTO TagTable (name, photo_id_list, time_created) VALUES (?, '', ?)").unwrap();
+            statement.bind(1, &name[..]).unwrap();
+            statement.bind(2, now).unwrap();
+            if let Err(e) = statement.next() {
+                warn!("failed to create tag {:?}: {}", name, e);
+                return Err(EIO);
+            }
+            library.tag_id(&name).ok_or(EIO)
+        })?;
+        Ok(make_dirattr(TAG | tag_id, time::Timespec{sec: now, nsec: 0}))
+    }
+
+    /// Retitle the tag directory `name` of `parent` and move it into the tag directory `newparent`.
+    fn move_tag(&mut self, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> Result<(), c_int> {
+        let inode = self.lookup_tag(parent, name)?.ino;
+        if !is_tag_dir(inode) {
+            return Err(EPERM);
+        }
+        let tag_id = inode & ID_MASK;
+        let old = self.tag_name(tag_id).ok_or(ENOENT)?;
+        let title = dir_title(newname)?;
+        if newparent != TAG {
+            let target = self.tag_name(newparent & ID_MASK).ok_or(ENOENT)?;
+            if target == old || target.starts_with(&format!("{}/", old)) {
+                return Err(EINVAL);
+            }
+        }
+        self.transaction(|library| {
+            let new = library.child_tag_name(newparent, title, old.starts_with('/'))?;
+            if new == old {
+                return Ok(());
+            }
+            library.rename_tag(tag_id, &old, &new)
+        })
+    }
+
+    /// Delete the tag directory `name` of `parent` if it has neither subtags nor tagged items.
+    fn remove_tag(&mut self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        let inode = self.lookup_tag(parent, name)?.ino;
+        if !is_tag_dir(inode) {
+            return Err(ENOTDIR);
+        }
+        let tag_id = inode & ID_MASK;
+        let tag_name = self.tag_name(tag_id).ok_or(ENOENT)?;
+        if !self.subtags(Some(&tag_name)).is_empty() || !self.tag_sources(tag_id).is_empty() {
+            return Err(ENOTEMPTY);
+        }
+        let mut statement = self.conn.prepare("DELETE FROM TagTable WHERE id = ?").unwrap();
+        statement.bind(1, tag_id as i64).unwrap();
+        statement.next().map(|_| ()).map_err(|e| {
+            warn!("failed to delete tag {:?}: {}", tag_name, e);
+            EIO
+        })
+    }
+
+    fn last_insert_id(&self) -> Result<u64, c_int> {
+        let mut statement = self.conn.prepare("SELECT last_insert_rowid()").unwrap();
+        statement.next().map_err(|_| EIO)?;
+        statement.read_u64(0).map_err(|_| EIO)
+    }
+
+    /// Add an event named `name`, or left unnamed for `Value::Null`, returning its id.
+    fn insert_event(&self, name: &sqlite::Value, now: i64) -> Result<u64, c_int> {
+        let mut statement = self.conn.prepare("INSERT INTO EventTable (name, time_created) VALUES (?, ?)").unwrap();
+        statement.bind(1, name).unwrap();
+        statement.bind(2, now).unwrap();
+        if let Err(e) = statement.next() {
+            warn!("failed to create event {:?}: {}", name, e);
+            return Err(EIO);
+        }
+        self.last_insert_id()
+    }
+
+    fn create_event(&mut self, name: &str) -> Result<FileAttr, c_int> {
+        let now = time::get_time().sec;
+        let event_id = self.insert_event(&sqlite::Value::String(name.to_owned()), now)?;
+        Ok(make_dirattr(EVENT | event_id, time::Timespec{sec: now, nsec: 0}))
+    }
+
+    /// Make `source` the photo representing event `event_id` unless it already has one.
+    fn set_event_primary(&self, event_id: u64, source: SourceId) {
+        // Older schemas lack primary_source_id and only know the primary photo.
+        if let Ok(mut statement) = self.conn.prepare("UPDATE EventTable SET primary_source_id = ? WHERE id = ? AND COALESCE(primary_source_id, '') = ''") {
+            statement.bind(1, &source.to_string()[..]).unwrap();
+            statement.bind(2, event_id as i64).unwrap();
+            if let Err(e) = statement.next() {
+                warn!("failed to set primary source of event id {}: {}", event_id, e);
+            }
+        }
+    }
+
+    fn rename_event(&mut self, name: &OsStr, newname: &OsStr) -> Result<(), c_int> {
+        let event_id = self.lookup_event(name)?.ino & ID_MASK;
+        let mut statement = self.conn.prepare("UPDATE EventTable SET name = ? WHERE id = ?").unwrap();
+        statement.bind(1, dir_title(newname)?).unwrap();
+        statement.bind(2, event_id as i64).unwrap();
+        statement.next().map(|_| ()).map_err(|e| {
+            warn!("failed to rename event id {}: {}", event_id, e);
+            EIO
+        })
+    }
+
+    /// Replace `source` as the photo representing event `event_id` by the earliest item left in
+    /// the event, or by none when it is empty.
+    fn replace_event_primary(&self, event_id: u64, source: SourceId) -> Result<(), c_int> {
+        let mut statement = self.conn.prepare(format!("{} ORDER BY timestamp ASC, kind ASC, id ASC LIMIT 1", media_query("event_id = ?2"))).unwrap();
+        statement.bind(2, event_id as i64).unwrap();
+        let primary = match statement.next() {
+            Ok(sqlite::State::Row) => {
+                let id = statement.read_u64(0).unwrap();
+                let primary = if statement.read::<i64>(5).unwrap() == 0 { SourceId::Photo(id) } else { SourceId::Video(id) };
+                sqlite::Value::String(primary.to_string())
+            },
+            _ => sqlite::Value::Null,
+        };
+        // Older schemas lack primary_source_id and only know the primary photo.
+        if let Ok(mut statement) = self.conn.prepare("UPDATE EventTable SET primary_source_id = ? WHERE id = ? AND primary_source_id = ?") {
+            statement.bind(1, &primary).unwrap();
+            statement.bind(2, event_id as i64).unwrap();
+            statement.bind(3, &source.to_string()[..]).unwrap();
+            statement.next().map_err(|e| {
+                warn!("failed to replace primary source of event id {}: {}", event_id, e);
+                EIO
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Move the photo or video `name` of the event directory `parent` into the event `newparent`,
+    /// which it represents when it had no primary photo yet. The item keeps its name, so moves
+    /// under another `newname` are refused.
+    fn move_to_event(&mut self, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> Result<(), c_int> {
+        if name != newname {
+            return Err(EINVAL);
+        }
+        let source = inode_source(self.lookup_event_contents(parent, name)?.ino).ok_or(EPERM)?;
+        let event_id = self.getattr_event(newparent)?.ino & ID_MASK;
+        self.transaction(|library| {
+            library.update_media(source, "event_id", &sqlite::Value::Integer(event_id as i64))?;
+            library.replace_event_primary(parent & ID_MASK, source)?;
+            library.set_event_primary(event_id, source);
+            Ok(())
+        })
+    }
+
+    /// Run a statement changing the database with `params` bound in order.
+    fn execute(&self, query: &str, params: &[sqlite::Value]) -> Result<(), sqlite::Error> {
+        let mut statement = self.conn.prepare(query)?;
+        for (i, param) in params.iter().enumerate() {
+            statement.bind(i + 1, param)?;
+        }
+        statement.next().map(|_| ())
+    }
+
+    /// Delete the photo or video `name` of `photos/` or `videos/` the way Shotwell does: its files
+    /// go to the trash, its row is replaced by a tombstone and it is removed from its tags.
+    fn delete_media(&mut self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        let inode = match parent {
+            PHOTO => self.lookup_photo(name)?.ino,
+            _ => self.lookup_video(name)?.ino,
+        };
+        let source = inode_source(inode).ok_or(EPERM)?;
+        let metadata = Metadata::load(&self.conn, source).ok_or(ENOENT)?;
+        let (table, id) = match source {
+            SourceId::Photo(id) => ("PhotoTable", id),
+            SourceId::Video(id) => ("VideoTable", id),
+        };
+        let id = sqlite::Value::Integer(id as i64);
+        let mut tagged = self.conn.prepare("SELECT id FROM TagTable WHERE ',' || photo_id_list || ',' LIKE ?").unwrap();
+        tagged.bind(1, &format!("%,{},%", source)[..]).unwrap();
+        let mut tag_ids = Vec::new();
+        while let Ok(sqlite::State::Row) = tagged.next() {
+            tag_ids.push(tagged.read_u64(0).unwrap());
+        }
+
+        self.conn.execute("BEGIN").map_err(|_| EIO)?;
+        let mut deleted = Ok(());
+        if let SourceId::Photo(_) = source {
+            deleted = self.execute("DELETE FROM BackingPhotoTable WHERE id = (SELECT editable_id FROM PhotoTable WHERE id = ?)", std::slice::from_ref(&id));
+        }
+        deleted = deleted.and_then(|_| self.execute(&format!("DELETE FROM {} WHERE id = ?", table), &[id]))
+                         .and_then(|_| self.execute("INSERT INTO TombstoneTable (filepath, filesize, md5, time_created, reason) VALUES (?, ?, ?, ?, 0)",
+                                                    &[sqlite::Value::String(metadata.filename.clone()),
+                                                      sqlite::Value::Integer(metadata.filesize as i64),
+                                                      sqlite::Value::String(metadata.md5.clone()),
+                                                      sqlite::Value::Integer(time::get_time().sec)]));
+        for tag_id in tag_ids {
+            let mut sources = self.tag_sources(tag_id);
+            sources.retain(|&tagged| tagged != source);
+            let list: String = sources.iter().map(|source| format!("{},", source)).collect();
+            deleted = deleted.and_then(|_| self.execute("UPDATE TagTable SET photo_id_list = ? WHERE id = ?",
+                                                        &[sqlite::Value::String(list), sqlite::Value::Integer(tag_id as i64)]));
+        }
+        if let Err(e) = deleted {
+            warn!("failed to delete {} from the database: {}", source, e);
+            let _ = self.conn.execute("ROLLBACK");
+            return Err(EIO);
+        }
+
+        // The files are trashed only once the rows are gone, and put back unless the deletion is
+        // committed, so the library never refers to files in the trash.
+        let mut trashed = Vec::new();
+        let mut result = Ok(());
+        for path in Some(&metadata.filename).into_iter().chain(metadata.edited_filename.as_ref()) {
+            match self.trash.trash(Path::new(path)) {
+                Ok(target) => trashed.push((target, path)),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => warn!("{} of {} is already gone", path, source),
+                Err(e) => {
+                    warn!("failed to trash {} of {}: {}", path, source, e);
+                    result = Err(EIO);
+                    break;
+                },
+            }
+        }
+        if result.is_ok() {
+            result = self.conn.execute("COMMIT").map_err(|e| {
+                warn!("failed to commit deletion of {}: {}", source, e);
+                EIO
+            });
+        }
+        if result.is_err() {
+            let _ = self.conn.execute("ROLLBACK");
+            for (target, path) in trashed {
+                if let Err(e) = self.trash.restore(&target, Path::new(path)) {
+                    warn!("failed to restore {} of {} from {:?}: {}", path, source, target, e);
+                }
+            }
+        }
+        result
+    }
+
+    fn upload_attr(&self, inode: u64) -> Result<FileAttr, c_int> {
+        let path = self.shared.uploads.lock().unwrap().get(&inode).map(|upload| upload.path.clone()).ok_or(ENOENT)?;
+        let attr = disk_fileattr(inode, &path).map_err(|_| EIO)?;
+        Ok(FileAttr { perm: 0o644, ..attr })
+    }
+
+    /// Start receiving the file `name` dropped into `import/`, returning its attributes and the
+    /// writer's file handle.
+    fn create_upload(&mut self, parent: u64, name: &OsStr) -> Result<(FileAttr, u64), c_int> {
+        if !self.writable {
+            return Err(EROFS);
+        }
+        if parent != IMPORT_DROP {
+            return Err(EPERM);
+        }
+        let name = name.to_str().ok_or(EINVAL)?;
+        let kind = MediaKind::of(name).ok_or(EINVAL)?;
+        if self.shared.uploads.lock().unwrap().values().any(|upload| upload.name == name) {
+            return Err(EEXIST);
+        }
+        fs::create_dir_all(&self.library).map_err(|e| {
+            warn!("failed to create {:?}: {}", self.library, e);
+            EIO
+        })?;
+        // Uploads left over from an earlier mount may hold the next numbers, so skip past them.
+        let (inode, path, file) = loop {
+            let inode = UPLOAD | self.next_upload;
+            self.next_upload += 1;
+            let path = self.library.join(format!(".{}.{}.part", inode & !UPLOAD, name));
+            match OpenOptions::new().read(true).write(true).create_new(true).open(&path) {
+                Ok(file) => break (inode, path, file),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => {
+                    warn!("failed to create {:?}: {}", path, e);
+                    return Err(EIO);
+                },
+            }
+        };
+        let fh = self.shared.next_handle.fetch_add(1, Ordering::SeqCst) + 1;
+        self.shared.uploads.lock().unwrap().insert(inode, Upload { name: name.to_owned(), kind, path, file, fh });
+        Ok((self.upload_attr(inode)?, fh))
+    }
+
+    fn write_upload(&mut self, inode: u64, offset: i64, data: &[u8]) -> Result<u32, c_int> {
+        let uploads = self.shared.uploads.lock().unwrap();
+        let upload = uploads.get(&inode).ok_or(if self.writable { EPERM } else { EROFS })?;
+        if offset < 0 {
+            return Err(EINVAL);
+        }
+        upload.file.write_all_at(data, offset as u64).map_err(|_| EIO)?;
+        Ok(data.len() as u32)
+    }
+
+    /// Change the size of a file being dropped into `import/`; other attributes are kept as they are.
+    fn set_upload_attr(&mut self, inode: u64, size: Option<u64>) -> Result<FileAttr, c_int> {
+        {
+            let uploads = self.shared.uploads.lock().unwrap();
+            let upload = uploads.get(&inode).ok_or(if self.writable { EPERM } else { EROFS })?;
+            if let Some(size) = size {
+                upload.file.set_len(size).map_err(|_| EIO)?;
+            }
+        }
+        self.upload_attr(inode)
+    }
+
+    /// Import id for media imported now: the current roll while files keep arriving, a new one
+    /// named after the current time otherwise.
+    fn import_id(&mut self, now: i64) -> i64 {
+        let import_id = match self.import_roll {
+            Some((import_id, last)) if now - last < IMPORT_ROLL_WINDOW => import_id,
+            _ => {
+                let mut statement = self.conn.prepare("SELECT COALESCE(MAX(import_id), 0) FROM \
+                                                       (SELECT import_id FROM PhotoTable UNION ALL SELECT import_id FROM VideoTable)").unwrap();
+                let latest = match statement.next() {
+                    Ok(sqlite::State::Row) => statement.read::<i64>(0).unwrap(),
+                    _ => 0,
+                };
+                now.max(latest + 1)
+            },
+        };
+        self.import_roll = Some((import_id, now));
+        import_id
+    }
+
+    /// Event for media taken at `exposure_time`: the event holding most media from the same day,
+    /// or a new unnamed one. Undated media are left out of events.
+    fn import_event(&self, exposure_time: i64, now: i64) -> Result<i64, c_int> {
+        if exposure_time <= 0 {
+            return Ok(-1);
+        }
+        let mut statement = self.conn.prepare("SELECT event_id FROM (SELECT event_id, exposure_time FROM PhotoTable UNION ALL SELECT event_id, exposure_time FROM VideoTable) \
+                                               WHERE event_id > 0 AND exposure_time > 0 \
+                                                 AND DATE(exposure_time, 'unixepoch', 'localtime') = DATE(?, 'unixepoch', 'localtime') \
+                                               GROUP BY event_id ORDER BY COUNT(*) DESC LIMIT 1").unwrap();
+        statement.bind(1, exposure_time).unwrap();
+        match statement.next() {
+            Ok(sqlite::State::Row) => Ok(statement.read::<i64>(0).unwrap()),
+            _ => self.insert_event(&sqlite::Value::Null, now).map(|event_id| event_id as i64),
+        }
+    }
+
+    /// Add the file at `path` in the library to `PhotoTable` or `VideoTable`.
+    fn import_media(&mut self, path: &Path, kind: MediaKind, probe: &importer::Probe) -> Result<SourceId, c_int> {
+        let now = time::get_time().sec;
+        let import_id = self.import_id(now);
+        self.transaction(|library| {
+            let event_id = library.import_event(probe.exposure_time, now)?;
+            let filename = sqlite::Value::String(path.to_string_lossy().into_owned());
+            let inserted = match kind {
+                MediaKind::Photo(file_format) => library.execute("INSERT INTO PhotoTable (filename, width, height, filesize, timestamp, exposure_time, orientation, \
+                                                                                          original_orientation, import_id, event_id, md5, time_created, file_format) \
+                                                                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7, ?8, ?9, ?10, ?5, ?11)",
+                                                                 &[filename, sqlite::Value::Integer(probe.width), sqlite::Value::Integer(probe.height),
+                                                                   sqlite::Value::Integer(probe.filesize as i64), sqlite::Value::Integer(now),
+                                                                   sqlite::Value::Integer(probe.exposure_time), sqlite::Value::Integer(probe.orientation),
+                                                                   sqlite::Value::Integer(import_id), sqlite::Value::Integer(event_id),
+                                                                   sqlite::Value::String(probe.md5.clone()), sqlite::Value::Integer(file_format)]),
+                MediaKind::Video => library.execute("INSERT INTO VideoTable (filename, width, height, clip_duration, is_interpretable, filesize, timestamp, \
+                                                                             exposure_time, import_id, event_id, md5, time_created) \
+                                                     VALUES (?1, ?2, ?3, 0, 0, ?4, ?5, ?6, ?7, ?8, ?9, ?5)",
+                                                    &[filename, sqlite::Value::Integer(probe.width), sqlite::Value::Integer(probe.height),
+                                                      sqlite::Value::Integer(probe.filesize as i64), sqlite::Value::Integer(now),
+                                                      sqlite::Value::Integer(probe.exposure_time), sqlite::Value::Integer(import_id),
+                                                      sqlite::Value::Integer(event_id), sqlite::Value::String(probe.md5.clone())]),
+            };
+            if let Err(e) = inserted {
+                warn!("failed to import {:?}: {}", path, e);
+                return Err(EIO);
+            }
+            let id = library.last_insert_id()?;
+            let source = match kind {
+                MediaKind::Photo(_) => SourceId::Photo(id),
+                MediaKind::Video => SourceId::Video(id),
+            };
+            if event_id > 0 {
+                library.set_event_primary(event_id as u64, source);
+            }
+            Ok(source)
+        })
+    }
+
+    /// Take the file dropped into `import/` as `inode` out of the directory once `fh`, the handle
+    /// of its writer, is released.
+    fn finish_upload(&mut self, inode: u64, fh: u64) -> Option<Upload> {
+        let mut uploads = self.shared.uploads.lock().unwrap();
+        match uploads.get(&inode) {
+            Some(upload) if upload.fh == fh => uploads.remove(&inode),
+            _ => None,
+        }
+    }
+
+    /// Move a finished upload into the library's date directories and import it.
+    fn import_upload(&mut self, upload: Upload) -> Result<(), c_int> {
+        let Upload { name, kind, path: part, file, .. } = upload;
+        drop(file);
+        let placed = importer::probe(&part, kind).and_then(|probe| {
+            let date = if probe.exposure_time > 0 { probe.exposure_time } else { time::get_time().sec };
+            Ok((importer::move_to_library(&part, &self.library, date, &name)?, probe))
+        });
+        let (path, probe) = placed.map_err(|e| {
+            warn!("failed to add {:?} to the library, left at {:?}: {}", name, part, e);
+            EIO
+        })?;
+        let source = self.import_media(&path, kind, &probe)?;
+        debug!("imported {:?} as {}", path, source);
+        Ok(())
+    }
+
+    fn readdir_import_drop(&mut self, mut reply: Listing, offset: i64) {
+        let mut idx = match reply.dots(IMPORT_DROP, ROOT, offset) {
+            Some(idx) => idx,
+            None => return reply.error(ENOENT),
+        };
+        let mut uploads: Vec<(u64, String)> = self.shared.uploads.lock().unwrap().iter().map(|(&inode, upload)| (inode, upload.name.clone())).collect();
+        uploads.sort();
+        for (inode, name) in uploads.into_iter().skip(idx as usize - 2) {
+            idx += 1;
+            if reply.add(inode, idx, FileType::RegularFile, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn lookup_import_drop(&mut self, name: &OsStr) -> Result<FileAttr, c_int> {
+        let inode = self.shared.uploads.lock().unwrap().iter()
+                                                   .find(|&(_, upload)| name == OsStr::new(&upload.name))
+                                                   .map(|(&inode, _)| inode)
+                                                   .ok_or(ENOENT)?;
+        self.upload_attr(inode)
+    }
+
+    /// Open the backing file of `inode` for reading, returning its file handle. Handle 0 is
+    /// returned for files read by other means: `metadata.json` and files dropped into `import/`.
+    fn open_handle(&mut self, inode: u64, flags: u32) -> Result<u64, c_int> {
+        if is_upload(inode) {
+            return if self.shared.uploads.lock().unwrap().contains_key(&inode) { Ok(0) } else { Err(ENOENT) };
+        }
+        if flags as c_int & O_ACCMODE != O_RDONLY || flags as c_int & O_TRUNC != 0 {
+            return Err(EROFS);
+        }
+        if inode & MEMBER_MASK == MEMBER_METADATA {
+            return Ok(0);
+        }
+        let path = self.backing_file(inode)?;
+        let file = File::open(&path).map_err(|e| {
+            debug!("failed to open {:?} for inode {}: {}", path, inode, e);
+            io_errno(&e)
+        })?;
+        let fh = self.shared.next_handle.fetch_add(1, Ordering::SeqCst) + 1;
+        self.shared.handles.lock().unwrap().insert(fh, Arc::new(file));
+        Ok(fh)
+    }
+
+    fn mkdir_entry(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        if !self.writable {
+            return Err(EROFS);
+        }
+        match parent {
+            x if is_tag_dir(x) => self.create_tag(x, dir_title(name)?),
+            EVENT => self.create_event(dir_title(name)?),
+            _ => Err(EPERM),
+        }
+    }
+
+    fn rmdir_entry(&mut self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        if !self.writable {
+            return Err(EROFS);
+        }
+        match parent {
+            x if is_tag_dir(x) => self.remove_tag(x, name),
+            _ => Err(EPERM),
+        }
+    }
+
+    fn rename_entry(&mut self, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> Result<(), c_int> {
+        if !self.writable {
+            return Err(EROFS);
+        }
+        match (parent, newparent) {
+            (x, y) if is_tag_dir(x) && is_tag_dir(y) => self.move_tag(x, name, y, newname),
+            (EVENT, EVENT) => self.rename_event(name, newname),
+            (x, y) if is_event_dir(x) && is_event_dir(y) && x != EVENT && y != EVENT && x != y => self.move_to_event(x, name, y, newname),
+            _ => Err(EPERM),
+        }
+    }
+
+    fn getattr_member(&mut self, inode: u64) -> Result<FileAttr, c_int> {
+        let media = inode & !MEMBER_MASK;
+        if !self.media_members(media).iter().any(|&(member, _)| member == inode) {
+            return Err(ENOENT);
+        }
+        match inode & MEMBER_MASK {
+            MEMBER_METADATA => {
+                let media_attr = self.getattr_media(media)?;
+                Ok(make_fileattr(inode, self.member_metadata(inode)?.len() as u64, media_attr.mtime))
+            },
+            MEMBER_ORIGINAL => self.getattr_media(media).map(|attr| FileAttr { ino: inode, ..attr }),
+            _ => disk_fileattr(inode, &self.member_file(inode)?).map_err(|_| ENOENT),
+        }
+    }
+
+    /// Resolve a source id to the photo or video row it refers to.
+    fn media_entry(&self, source: SourceId) -> Option<MediaEntry> {
+        let (query, id, kind) = match source {
+            SourceId::Photo(id) => (format!("SELECT filename, timestamp, title, filesize FROM PhotoTable WHERE id = ? AND {}", self.visible_photo("flags")), id, PHOTO),
+            SourceId::Video(id) => ("SELECT filename, timestamp, title, filesize FROM VideoTable WHERE id = ?".to_owned(), id, VIDEO),
+        };
+        let mut statement = self.conn.prepare(query).unwrap();
+        statement.bind(1, id as i64).unwrap();
+        if let Ok(sqlite::State::Row) = statement.next() {
+            let filename = statement.read_text(0).unwrap_or_default();
+            let timestamp = statement.read::<i64>(1).unwrap();
+            let title = statement.read_text(2).unwrap_or_default();
+            Some(MediaEntry {
+                inode: id | kind,
+                name: media_name(id, file_extension(&filename), timestamp, &title),
+                filesize: statement.read_u64(3).unwrap(),
+                timestamp: time::Timespec{sec: timestamp, nsec: 0},
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Path of the file whose contents are served for `inode`.
+    fn backing_file(&self, inode: u64) -> Result<PathBuf, c_int> {
+        let (query, media_id) = match inode {
+            PHOTO | VIDEO | EDITED | RENDERED => return Err(ENOENT),
+            x if x & THUMBNAILS == THUMBNAILS => return self.thumbnail_file(x),
+            x if is_upload(x) => return self.shared.uploads.lock().unwrap().get(&x).map(|upload| upload.path.clone()).ok_or(ENOENT),
+            x if x & MEMBER_MASK != 0 => return self.member_file(x),
+            x if x & RENDERED == RENDERED => return self.rendered_file(x & !(RENDERED | PHOTO), true),
+            x if x & EDITED == EDITED => ("SELECT COALESCE(b.filepath, p.filename) FROM PhotoTable p LEFT JOIN BackingPhotoTable b ON b.id = p.editable_id WHERE p.id = ?", x & !(EDITED | PHOTO)),
+            x if x & PHOTO == PHOTO => ("SELECT filename FROM PhotoTable WHERE id = ?", x & !PHOTO),
+            x if x & VIDEO == VIDEO => ("SELECT filename FROM VideoTable WHERE id = ?", x & !VIDEO),
+            _ => return Err(ENOENT),
+        };
+        let mut statement = self.conn.prepare(query).unwrap();
+        statement.bind(1, media_id as i64).unwrap();
+        match statement.next() {
+            Ok(sqlite::State::Row) => statement.read_text(0).map(PathBuf::from).ok_or(ENOENT),
+            _ => Err(ENOENT),
+        }
+    }
+
+    /// Render the photo with Shotwell's transformations applied, or reuse the cached result.
+    /// Without `render`, only the path the rendering is cached at is returned.
+    fn rendered_file(&self, photo_id: u64, render: bool) -> Result<PathBuf, c_int> {
+        let mut statement = self.conn.prepare(rendered_query("id = ?")).unwrap();
+        statement.bind(1, photo_id as i64).unwrap();
+        if let Ok(sqlite::State::Row) = statement.next() {
+            let filename = statement.read_text(1).unwrap_or_default();
+            let orientation = statement.read::<i64>(4).unwrap();
+            let transformations = statement.read_text(5).unwrap_or_default();
+            let rendered = if render {
+                self.render_cache.get(photo_id, Path::new(&filename), orientation, &transformations)
+            } else {
+                self.render_cache.entry(photo_id, Path::new(&filename), orientation, &transformations)
+            };
+            rendered.map_err(|e| {
+                warn!("failed to render photo id {} from {}: {}", photo_id, filename, e);
+                EIO
+            })
+        } else {
+            Err(ENOENT)
+        }
+    }
+
+    /// Attributes of a rendered photo: the size of the rendered JPEG and the time it was rendered.
+    ///
+    /// Rendering is left to `open`, so a photo that is not rendered yet has size 0 and the time it
+    /// was taken. Rendered files are opened with direct I/O so reads are not cut to that size.
+    fn rendered_attr(&self, photo_id: u64) -> Result<FileAttr, c_int> {
+        let mut statement = self.conn.prepare(rendered_query("id = ?")).unwrap();
+        statement.bind(1, photo_id as i64).unwrap();
+        if let Ok(sqlite::State::Row) = statement.next() {
+            let filename = statement.read_text(1).unwrap_or_default();
+            let orientation = statement.read::<i64>(4).unwrap();
+            let transformations = statement.read_text(5).unwrap_or_default();
+            match self.render_cache.cached(photo_id, Path::new(&filename), orientation, &transformations) {
+                Some(path) => disk_fileattr(RENDERED | PHOTO | photo_id, &path).map_err(|_| EIO),
+                None => Ok(make_fileattr(RENDERED | PHOTO | photo_id, 0, time::Timespec{sec: statement.read::<i64>(2).unwrap(), nsec: 0})),
+            }
+        } else {
+            Err(ENOENT)
+        }
+    }
+
+    /// Cached thumbnail of the media file `inode` within a `thumbnails/` mirror.
+    fn thumbnail_file(&self, inode: u64) -> Result<PathBuf, c_int> {
+        let mirror = inode & MIRROR_MASK;
+        let source = inode_source(inode & !mirror).ok_or(ENOENT)?;
+        Ok(self.thumbnails.path(mirror_size(mirror), source))
+    }
+
+    fn thumbnail_attr(&self, inode: u64) -> Result<FileAttr, c_int> {
+        let path = self.thumbnail_file(inode)?;
+        disk_fileattr(inode, &path).map_err(|_| ENOENT)
+    }
+
+    /// Attributes of `inode` within a `thumbnails/` mirror: directories mirror the attributes of
+    /// the directory they reflect, files are the cached thumbnails.
+    fn getattr_thumbnails(&mut self, inode: u64) -> Result<FileAttr, c_int> {
+        let mirror = inode & MIRROR_MASK;
+        match inode & !mirror {
+            ROOT => Ok(make_dirattr(inode, NOTIME)),
+            base if inode_source(base).is_some() => self.thumbnail_attr(inode),
+            base => self.attr(base).map(|attr| FileAttr { ino: inode, ..attr }),
+        }
+    }
+
+    /// Subdirectories of the `by-date/` directory `inode` with their attributes, computed by
+    /// grouping media on the next date component: the size of each directory is its number of
+    /// entries and its time is the latest exposure time within.
+    fn date_subdirs(&self, inode: u64) -> Vec<(String, FileAttr)> {
+        let (unit, entries, shift, width) = match date_parts(inode) {
+            (0, _, _) => ("%Y", "COUNT(DISTINCT strftime('%m', exposure_time, 'unixepoch', 'localtime'))", YEAR_SHIFT, 4),
+            (_, 0, _) => ("%m", "COUNT(DISTINCT strftime('%d', exposure_time, 'unixepoch', 'localtime'))", MONTH_SHIFT, 2),
+            (_, _, 0) => ("%d", "COUNT(*)", 0, 2),
+            _ => return Vec::new(),
+        };
+        let query = format!("SELECT CAST(strftime('{}', exposure_time, 'unixepoch', 'localtime') AS INTEGER) AS unit, {}, MAX(exposure_time) \
+                             FROM ({}) GROUP BY unit ORDER BY unit ASC",
+                            unit, entries, media_query(&self.visible(DATED_FILTER)));
+        let mut statement = self.conn.prepare(query).unwrap();
+        statement.bind(2, &date_pattern(inode)[..]).unwrap();
+        let mut subdirs = Vec::new();
+        while let Ok(sqlite::State::Row) = statement.next() {
+            let number = statement.read_u64(0).unwrap();
+            let timestamp = time::Timespec{sec: statement.read::<i64>(2).unwrap(), nsec: 0};
+            let attr = FileAttr { size: statement.read_u64(1).unwrap(), ..make_dirattr(inode | number << shift, timestamp) };
+            subdirs.push((format!("{:0width$}", number, width = width), attr));
+        }
+        subdirs
+    }
+
+    fn getattr_undated(&mut self) -> Result<FileAttr, c_int> {
+        let query = format!("SELECT COUNT(*), COALESCE(MAX(timestamp), {}) FROM ({})", NOTIME.sec, media_query(&self.visible(UNDATED_FILTER)));
+        let mut statement = self.conn.prepare(query).unwrap();
+        if let Ok(sqlite::State::Row) = statement.next() {
+            let timestamp = time::Timespec{sec: statement.read::<i64>(1).unwrap(), nsec: 0};
+            Ok(FileAttr { size: statement.read_u64(0).unwrap(), ..make_dirattr(UNDATED, timestamp) })
+        } else {
+            Err(ENOENT)
+        }
+    }
+
+    fn getattr_date(&mut self, inode: u64) -> Result<FileAttr, c_int> {
+        self.date_subdirs(date_parent(inode)).into_iter()
+                                             .map(|(_, attr)| attr)
+                                             .find(|attr| attr.ino == inode)
+                                             .ok_or(ENOENT)
+    }
+
+    fn getattr_search(&mut self, inode: u64) -> Result<FileAttr, c_int> {
+        let mut statement = self.conn.prepare("SELECT id FROM SavedSearchDBTable WHERE id = ?").unwrap();
+        statement.bind(1, (inode & !SEARCH) as i64).unwrap();
+        if let Ok(sqlite::State::Row) = statement.next() {
+            Ok(make_dirattr(inode, NOTIME))
+        } else {
+            Err(ENOENT)
+        }
+    }
+
+    fn getattr_person(&mut self, inode: u64) -> Result<FileAttr, c_int> {
+        let mut statement = self.conn.prepare("SELECT time_created FROM FaceTable WHERE id = ?").unwrap();
+        statement.bind(1, (inode & !PERSON) as i64).unwrap();
+        if let Ok(sqlite::State::Row) = statement.next() {
+            let timestamp = time::Timespec{sec: statement.read::<i64>(0).unwrap(), nsec: 0};
+            Ok(make_dirattr(inode, timestamp))
+        } else {
+            Err(ENOENT)
+        }
+    }
+
+    /// Import rolls as `(import_id, name)`, oldest first. Rolls are named after their import time.
+    fn import_rolls(&self) -> Vec<(u64, String)> {
+        let query = format!("SELECT DISTINCT import_id FROM ({}) ORDER BY import_id ASC", media_query(&self.visible("import_id > 0")));
+        let mut statement = self.conn.prepare(query).unwrap();
+        let mut rolls = Vec::new();
+        while let Ok(sqlite::State::Row) = statement.next() {
+            let import_id = statement.read_u64(0).unwrap();
+            let imported = time::at(time::Timespec{sec: import_id as i64, nsec: 0});
+            rolls.push((import_id, imported.strftime("%Y-%m-%d %H:%M:%S").unwrap().to_string()));
+        }
+        rolls
+    }
+
+    fn last_import(&self) -> Option<u64> {
+        self.import_rolls().pop().map(|(import_id, _)| import_id)
+    }
+
+    fn getattr_import(&mut self, inode: u64) -> Result<FileAttr, c_int> {
+        let import_id = match inode {
+            LAST_IMPORT => self.last_import().ok_or(ENOENT)?,
+            x => x & !IMPORT,
+        };
+        if self.import_rolls().iter().any(|&(roll, _)| roll == import_id) {
+            Ok(make_dirattr(inode, time::Timespec{sec: import_id as i64, nsec: 0}))
+        } else {
+            Err(ENOENT)
+        }
+    }
+
+    fn getattr_tag(&mut self, inode: u64) -> Result<FileAttr, c_int> {
+        let mut statement = self.conn.prepare("SELECT time_created FROM TagTable WHERE id = ?").unwrap();
+        statement.bind(1, (inode & !TAG) as i64).unwrap();
+        if let Ok(sqlite::State::Row) = statement.next() {
+            let timestamp = time::Timespec{sec: statement.read::<i64>(0).unwrap(), nsec: 0};
+            Ok(make_dirattr(inode, timestamp))
+        } else {
+            Err(ENOENT)
+        }
+    }
+
+    fn getattr_event(&mut self, inode: u64) -> Result<FileAttr, c_int> {
+        let mut statement = self.conn.prepare("SELECT time_created FROM EventTable WHERE id = ?").unwrap();
+        statement.bind(1, (inode & !EVENT) as i64).unwrap();
+        if let Ok(sqlite::State::Row) = statement.next() {
+            let timestamp = time::Timespec{sec: statement.read::<i64>(0).unwrap(), nsec: 0};
+            Ok(make_dirattr(inode, timestamp))
+        } else {
+            Err(ENOENT)
+        }
+    }
+
+    fn getattr_video(&mut self, inode: u64) -> Result<FileAttr, c_int> {
+        let mut statement = self.conn.prepare("SELECT exposure_time, filesize FROM VideoTable WHERE id = ?").unwrap();
+        statement.bind(1, (inode & !VIDEO) as i64).unwrap();
+        if let Ok(sqlite::State::Row) = statement.next() {
+            let timestamp = time::Timespec{sec: statement.read::<i64>(0).unwrap(), nsec: 0};
+            let filesize = statement.read_u64(1).unwrap();
+            Ok(make_fileattr(inode, filesize, timestamp))
+        } else {
+            Err(ENOENT)
+        }
+    }
+
+    fn getattr_photo(&mut self, inode: u64) -> Result<FileAttr, c_int> {
+        let mut statement = self.conn.prepare("SELECT exposure_time, filesize FROM PhotoTable WHERE id = ?").unwrap();
+        statement.bind(1, (inode & !PHOTO) as i64).unwrap();
+        if let Ok(sqlite::State::Row) = statement.next() {
+            let timestamp = time::Timespec{sec: statement.read::<i64>(0).unwrap(), nsec: 0};
+            let filesize = statement.read_u64(1).unwrap();
+            Ok(make_fileattr(inode, filesize, timestamp))
+        } else {
+            Err(ENOENT)
+        }
+    }
+
+    fn getattr_media(&mut self, inode: u64) -> Result<FileAttr, c_int> {
+        if inode & PHOTO == PHOTO {
+            self.getattr_photo(inode)
+        } else {
+            self.getattr_video(inode)
+        }
+    }
+
+    fn getattr_edited(&mut self, inode: u64) -> Result<FileAttr, c_int> {
+        let mut statement = self.conn.prepare(format!("{} WHERE p.id = ?", EDITED_QUERY)).unwrap();
+        statement.bind(1, (inode & !(EDITED | PHOTO)) as i64).unwrap();
+        if let Ok(sqlite::State::Row) = statement.next() {
+            let timestamp = time::Timespec{sec: statement.read::<i64>(2).unwrap(), nsec: 0};
+            let filesize = statement.read_u64(4).unwrap();
+            Ok(make_fileattr(inode, filesize, timestamp))
+        } else {
+            Err(ENOENT)
+        }
+    }
+
+    fn getattr_rendered(&mut self, inode: u64) -> Result<FileAttr, c_int> {
+        self.rendered_attr(inode & !(RENDERED | PHOTO))
+    }
+
+    fn readdir_root(&mut self, mut reply: Listing, offset: i64) {
+        let mut idx = match reply.dots(ROOT, ROOT, offset) {
+            Some(idx) => idx,
+            None => return reply.error(ENOENT),
+        };
+        let mut entries = vec![(PHOTO, "photos"),
+                               (VIDEO, "videos"),
+                               (TAG, "tags"),
+                               (EVENT, "events"),
+                               (EDITED, "photos-edited"),
+                               (RENDERED, "rendered"),
+                               (THUMBNAILS, "thumbnails"),
+                               (RATINGS, "ratings"),
+                               (FLAGGED, "flagged"),
+                               (HIDDEN, "hidden"),
+                               (BY_DATE, "by-date")];
+        if self.has_table("SavedSearchDBTable") {
+            entries.push((SEARCH, "searches"));
+        }
+        if self.has_faces() {
+            entries.push((PERSON, "people"));
+        }
+        entries.push((IMPORT, "imports"));
+        if self.last_import().is_some() {
+            entries.push((LAST_IMPORT, "last-import"));
+        }
+        if self.writable {
+            entries.push((IMPORT_DROP, "import"));
+        }
+        for (inode, name) in entries.into_iter().skip(idx as usize - 2) {
+            idx += 1;
+            if reply.add(inode, idx, FileType::Directory, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn readdir_thumbnail_sizes(&mut self, mut reply: Listing, offset: i64) {
+        let mut idx = match reply.dots(THUMBNAILS, ROOT, offset) {
+            Some(idx) => idx,
+            None => return reply.error(ENOENT),
+        };
+        for (inode, name) in [(THUMBNAILS | ROOT, "128"), (THUMBNAILS | THUMB_360 | ROOT, "360")].iter().skip(idx as usize - 2) {
+            idx += 1;
+            if reply.add(*inode, idx, FileType::Directory, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    /// List a directory of a `thumbnails/` mirror by listing the directory it reflects.
+    fn readdir_thumbnails(&mut self, inode: u64, reply: fuse::ReplyDirectory, offset: i64) {
+        let mirror = inode & MIRROR_MASK;
+        match inode & !mirror {
+            ROOT => {
+                let mut reply = Listing::new(reply, false, false);
+                let mut idx = match reply.dots(inode, THUMBNAILS, offset) {
+                    Some(idx) => idx,
+                    None => return reply.error(ENOENT),
+                };
+                for (dir, name) in [(PHOTO, "photos"), (VIDEO, "videos"), (TAG, "tags"), (EVENT, "events")].iter().skip(idx as usize - 2) {
+                    idx += 1;
+                    if reply.add(dir | mirror, idx, FileType::Directory, name) {
+                        break;
+                    }
+                }
+                reply.ok();
+            },
+            base if !matches!(base & !ID_MASK, PHOTO | VIDEO | TAG | EVENT) => reply.error(ENOENT),
+            base => self.readdir_listing(base, Listing::mirrored(reply, mirror, self.thumbnails.clone()), offset),
+        }
+    }
+
+    fn readdir_ratings(&mut self, mut reply: Listing, offset: i64) {
+        let mut idx = match reply.dots(RATINGS, ROOT, offset) {
+            Some(idx) => idx,
+            None => return reply.error(ENOENT),
+        };
+        for (i, &(name, _)) in RATING_DIRS.iter().enumerate().skip(idx as usize - 2) {
+            idx += 1;
+            if reply.add(RATINGS | (i as u64 + 1), idx, FileType::Directory, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn readdir_rating_contents(&mut self, inode: u64, reply: Listing, offset: i64) {
+        match RATING_DIRS.get(((inode & !RATINGS) as usize).wrapping_sub(1)) {
+            Some(&(_, filter)) => {
+                let filter = self.visible(filter);
+                self.readdir_media(inode, RATINGS, &filter, &[], reply, offset)
+            },
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn readdir_date(&mut self, inode: u64, mut reply: Listing, offset: i64) {
+        if date_parts(inode).2 != 0 {
+            let params = [sqlite::Value::String(date_pattern(inode))];
+            let filter = self.visible(DATED_FILTER);
+            self.readdir_media(inode, date_parent(inode), &filter, &params, reply, offset);
+            return;
+        }
+        let mut idx = match reply.dots(inode, date_parent(inode), offset) {
+            Some(idx) => idx,
+            None => return reply.error(ENOENT),
+        };
+        let mut entries: Vec<(u64, String)> = self.date_subdirs(inode).into_iter().map(|(name, attr)| (attr.ino, name)).collect();
+        if inode == BY_DATE {
+            entries.push((UNDATED, "undated".to_owned()));
+        }
+        for (entry, name) in entries.into_iter().skip(idx as usize - 2) {
+            idx += 1;
+            if reply.add(entry, idx, FileType::Directory, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn readdir_searches(&mut self, mut reply: Listing, offset: i64) {
+        let mut idx = match reply.dots(SEARCH, ROOT, offset) {
+            Some(idx) => idx,
+            None => return reply.error(ENOENT),
+        };
+        let mut statement = self.conn.prepare("SELECT id, name FROM SavedSearchDBTable ORDER BY name ASC LIMIT ?, 100").unwrap();
+        statement.bind(1, idx - 2).unwrap();
+        while let Ok(sqlite::State::Row) = statement.next() {
+            let search_id = statement.read_u64(0).unwrap();
+            let name = statement.read_text(1).unwrap_or_default();
+            debug!("saved search id {} has utf name {:?}", search_id, name);
+            idx += 1;
+            if reply.add(search_id | SEARCH, idx, FileType::Directory, format!("[{}] {}", search_id, name)) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn readdir_search_contents(&mut self, inode: u64, reply: Listing, offset: i64) {
+        match SavedSearch::load(&self.conn, inode & !SEARCH) {
+            Some(search) => {
+                let filter = self.visible(&search.filter());
+                self.readdir_media(inode, SEARCH, &filter, search.params(), reply, offset);
+            },
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn readdir_people(&mut self, mut reply: Listing, offset: i64) {
+        let mut idx = match reply.dots(PERSON, ROOT, offset) {
+            Some(idx) => idx,
+            None => return reply.error(ENOENT),
+        };
+        let mut statement = self.conn.prepare("SELECT id, name FROM FaceTable ORDER BY name ASC LIMIT ?, 100").unwrap();
+        statement.bind(1, idx - 2).unwrap();
+        while let Ok(sqlite::State::Row) = statement.next() {
+            let face_id = statement.read_u64(0).unwrap();
+            let name = statement.read_text(1).unwrap_or_default();
+            debug!("person id {} has utf name {:?}", face_id, name);
+            idx += 1;
+            if reply.add(face_id | PERSON, idx, FileType::Directory, format!("[{}] {}", face_id, name)) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn readdir_person_contents(&mut self, inode: u64, reply: Listing, offset: i64) {
+        let params = [sqlite::Value::Integer((inode & !PERSON) as i64)];
+        let filter = self.visible(PERSON_FILTER);
+        self.readdir_media(inode, PERSON, &filter, &params, reply, offset);
+    }
+
+    fn readdir_imports(&mut self, mut reply: Listing, offset: i64) {
+        let mut idx = match reply.dots(IMPORT, ROOT, offset) {
+            Some(idx) => idx,
+            None => return reply.error(ENOENT),
+        };
+        for (import_id, name) in self.import_rolls().into_iter().skip(idx as usize - 2) {
+            idx += 1;
+            if reply.add(import_id | IMPORT, idx, FileType::Directory, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn readdir_import_contents(&mut self, inode: u64, reply: Listing, offset: i64) {
+        let (import_id, parent) = match inode {
+            LAST_IMPORT => match self.last_import() {
+                Some(import_id) => (import_id, ROOT),
+                None => {
+                    reply.error(ENOENT);
+                    return;
+                },
+            },
+            x => (x & !IMPORT, IMPORT),
+        };
+        let params = [sqlite::Value::Integer(import_id as i64)];
+        let filter = self.visible(IMPORT_FILTER);
+        self.readdir_media(inode, parent, &filter, &params, reply, offset);
+    }
+
+    fn readdir_members(&mut self, inode: u64, mut reply: Listing, offset: i64) {
+        let mut idx = match reply.dots(inode, inode & !ID_MASK, offset) {
+            Some(idx) => idx,
+            None => return reply.error(ENOENT),
+        };
+        for (member, name) in self.media_members(inode).into_iter().skip(idx as usize - 2) {
+            idx += 1;
+            if reply.add(member, idx, FileType::RegularFile, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn readdir_events(&mut self, mut reply: Listing, offset: i64) {
+        let mut idx = match reply.dots(EVENT, ROOT, offset) {
+            Some(idx) => idx,
+            None => return reply.error(ENOENT),
+        };
+
+        let mut statement = self.conn.prepare("SELECT id, name, time_created FROM EventTable ORDER BY time_created ASC LIMIT ?, 100").unwrap();
+        statement.bind(1, idx - 2).unwrap();
+        while let Ok(sqlite::State::Row) = statement.next() {
+            let event_id = statement.read_u64(0).unwrap();
+            let inode = event_id | EVENT;
+            let name = statement.read_text(1).unwrap_or_default();
+            let name = if !name.is_empty() {
+                debug!("event id {} has utf name {:?}", event_id, name);
+                name
+            } else {
+                let timestamp = time::at(time::Timespec{sec: statement.read::<i64>(2).unwrap(), nsec: 0});
+                let tm = timestamp.strftime("%Y-%m-%d %H:%M").unwrap().to_string();
+                debug!("event id {} event name is empty, using timestamp `{}`", event_id, tm);
+                tm
+            };
+            idx += 1;
+            if reply.add(inode, idx, FileType::Directory, format!("[{}] {}", event_id, name)) {
+                break;
+            }
+        };
+        reply.ok();
+    }
+
+    fn readdir_event_contents(&mut self, inode: u64, reply: Listing, offset: i64) {
+        debug!("readdir for event id {}", inode & !EVENT);
+        let params = [sqlite::Value::Integer((inode & !EVENT) as i64)];
+        let filter = self.visible("event_id = ?2");
+        self.readdir_media(inode, EVENT, &filter, &params, reply, offset);
+    }
+
+    /// List photos and videos matching `filter` (see `media_query`) ordered by timestamp.
+    ///
+    /// Entry offsets are 1-based positions of the next entry: `.` and `..` take the first two
+    /// slots, so media rows start at offset 2.
+    fn readdir_media(&mut self, inode: u64, parent: u64, filter: &str, params: &[sqlite::Value], mut reply: Listing, offset: i64) {
+        let mut idx = match reply.dots(inode, parent, offset) {
+            Some(idx) => idx,
+            None => return reply.error(ENOENT),
+        };
+
+        let query = format!("{} ORDER BY timestamp ASC, kind ASC, id ASC LIMIT ?1, 100", media_query(filter));
+        let mut statement = self.conn.prepare(query).unwrap();
+        statement.bind(1, idx - 2).unwrap();
+        for (i, param) in params.iter().enumerate() {
+            statement.bind(i + 2, param).unwrap();
+        }
+        while let Ok(sqlite::State::Row) = statement.next() {
+            let media_id = statement.read_u64(0).unwrap();
+            let kind = if statement.read::<i64>(5).unwrap() == 0 { PHOTO } else { VIDEO };
+            let filename = statement.read_text(1).unwrap_or_default();
+            let title = statement.read_text(3).unwrap_or_default();
+            let name = media_name(media_id, file_extension(&filename), statement.read::<i64>(2).unwrap(), &title);
+            debug!("media id {} kind {} listed as {:?}", media_id, kind, name);
+            idx += 1;
+            if reply.add(media_id | kind, idx, FileType::RegularFile, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn readdir_tags(&mut self, mut reply: Listing, offset: i64) {
+        let mut idx = match reply.dots(TAG, ROOT, offset) {
+            Some(idx) => idx,
+            None => return reply.error(ENOENT),
+        };
+
+        for (tag_id, title) in self.subtags(None).into_iter().skip(idx as usize - 2) {
+            debug!("tag id {} has utf name {:?}", tag_id, title);
+            idx += 1;
+            if reply.add(tag_id | TAG, idx, FileType::Directory, format!("[{}] {}", tag_id, title)) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn readdir_tag_contents(&mut self, inode: u64, mut reply: Listing, offset: i64) {
+        debug!("readdir for tag id {}", inode & !TAG);
+        let name = match self.tag_name(inode & !TAG) {
+            Some(name) => name,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let parent = self.tag_parent(&name);
+        let mut idx = match reply.dots(inode, parent, offset) {
+            Some(idx) => idx,
+            None => return reply.error(ENOENT),
+        };
+
+        let subtags = self.subtags(Some(&name));
+        let subtag_count = subtags.len() as i64;
+        for (tag_id, title) in subtags.into_iter().skip(idx as usize - 2) {
+            debug!("subtag id {} has utf name {:?}", tag_id, title);
+            idx += 1;
+            if reply.add(tag_id | TAG, idx, FileType::Directory, format!("[{}] {}", tag_id, title)) {
+                reply.ok();
+                return;
+            }
+        }
+
+        let skip = (idx - 2 - subtag_count) as usize;
+        for source in self.tag_sources(inode & !TAG).into_iter().skip(skip).take(100) {
+            idx += 1;
+            if let Some(entry) = self.media_entry(source) {
+                debug!("source {:?} listed as {:?}", source, entry.name);
+                if reply.add(entry.inode, idx, FileType::RegularFile, entry.name) {
+                    break;
+                }
+            }
+        }
+        reply.ok();
+    }
+
+    fn readdir_photos(&mut self, mut reply: Listing, offset: i64) {
+        let mut idx = match reply.dots(PHOTO, ROOT, offset) {
+            Some(idx) => idx,
+            None => return reply.error(ENOENT),
+        };
+        let mut statement = self.conn.prepare(format!("SELECT id, filename, timestamp, title FROM PhotoTable WHERE {} ORDER BY timestamp ASC, id ASC LIMIT ?, 100", self.visible_photo("flags"))).unwrap();
+        statement.bind(1, idx - 2).unwrap();
+        while let Ok(sqlite::State::Row) = statement.next() {
+            let photo_id = statement.read_u64(0).unwrap();
+            let inode = photo_id | PHOTO;
+            let filename = statement.read_text(1).unwrap_or_default();
+            let extension = filename.rfind('.').map(|x| &filename[x+1..]).unwrap_or("");
+            let title = statement.read_text(3).unwrap_or_default();
+            let title = if !title.is_empty() {
+                debug!("photo id {} has utf name {:?}", photo_id, title);
+                title
+            } else {
+                let timestamp = time::at(time::Timespec{sec: statement.read::<i64>(2).unwrap(), nsec: 0});
+                let tm = timestamp.strftime("%Y-%m-%d %H:%M").unwrap().to_string();
+                debug!("photo id {} title is empty, using timestamp `{}`", photo_id, tm);
+                tm
+            };
+            idx += 1;
+            if reply.add(inode, idx, FileType::RegularFile, format!("({}) {}.{}", photo_id, title, extension)) {
+                break;
+            }
+        };
+        reply.ok();
+    }
+
+    fn readdir_edited(&mut self, mut reply: Listing, offset: i64) {
+        let mut idx = match reply.dots(EDITED, ROOT, offset) {
+            Some(idx) => idx,
+            None => return reply.error(ENOENT),
+        };
+        let mut statement = self.conn.prepare(format!("{} WHERE {} ORDER BY p.timestamp ASC, p.id ASC LIMIT ?, 100", EDITED_QUERY, self.visible_photo("p.flags"))).unwrap();
+        statement.bind(1, idx - 2).unwrap();
+        while let Ok(sqlite::State::Row) = statement.next() {
+            let photo_id = statement.read_u64(0).unwrap();
+            let filename = statement.read_text(1).unwrap_or_default();
+            let title = statement.read_text(3).unwrap_or_default();
+            let name = media_name(photo_id, file_extension(&filename), statement.read::<i64>(2).unwrap(), &title);
+            debug!("edited photo id {} listed as {:?}", photo_id, name);
+            idx += 1;
+            if reply.add(photo_id | PHOTO | EDITED, idx, FileType::RegularFile, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn readdir_rendered(&mut self, mut reply: Listing, offset: i64) {
+        let mut idx = match reply.dots(RENDERED, ROOT, offset) {
+            Some(idx) => idx,
+            None => return reply.error(ENOENT),
+        };
+        let mut statement = self.conn.prepare(format!("{} ORDER BY timestamp ASC, id ASC LIMIT ?, 100", rendered_query(&self.visible_photo("flags")))).unwrap();
+        statement.bind(1, idx - 2).unwrap();
+        while let Ok(sqlite::State::Row) = statement.next() {
+            let photo_id = statement.read_u64(0).unwrap();
+            let title = statement.read_text(3).unwrap_or_default();
+            let name = media_name(photo_id, "jpg", statement.read::<i64>(2).unwrap(), &title);
+            idx += 1;
+            if reply.add(photo_id | PHOTO | RENDERED, idx, FileType::RegularFile, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn readdir_videos(&mut self, mut reply: Listing, offset: i64) {
+        let mut idx = match reply.dots(VIDEO, ROOT, offset) {
+            Some(idx) => idx,
+            None => return reply.error(ENOENT),
+        };
+        let mut statement = self.conn.prepare("SELECT id, filename, timestamp, title FROM VideoTable ORDER BY timestamp ASC LIMIT ?, 100").unwrap();
+        statement.bind(1, idx - 2).unwrap();
+        while let Ok(sqlite::State::Row) = statement.next() {
+            let video_id = statement.read_u64(0).unwrap();
+            let inode = video_id | VIDEO;
+            let filename = statement.read_text(1).unwrap_or_default();
+            let extension = filename.rfind('.').map(|x| &filename[x+1..]).unwrap_or("");
+            let title = statement.read_text(3).unwrap_or_default();
+            let title = if !title.is_empty() {
+                debug!("video id {} has utf name {:?}", video_id, title);
+                title
+            } else {
+                let timestamp = time::at(time::Timespec{sec: statement.read::<i64>(2).unwrap(), nsec: 0});
+                let tm = timestamp.strftime("%Y-%m-%d %H:%M").unwrap().to_string();
+                debug!("video id {} title is empty, using timestamp `{}`", video_id, tm);
+                tm
+            };
+            idx += 1;
+            if reply.add(inode, idx, FileType::RegularFile, format!("({}) {}.{}", video_id, title, extension)) {
+                break;
+            }
+        };
+        reply.ok();
+    }
+
+    fn lookup_root(&mut self, name: &OsStr) -> Result<FileAttr, c_int> {
+        match name.to_str() {
+            Some("/") => Ok(ROOT_ATTR),
+            Some("photos") => Ok(PHOTO_ATTR),
+            Some("videos") => Ok(VIDEO_ATTR),
+            Some("tags") => Ok(TAG_ATTR),
+            Some("events") => Ok(EVENT_ATTR),
+            Some("photos-edited") => Ok(EDITED_ATTR),
+            Some("rendered") => Ok(RENDERED_ATTR),
+            Some("thumbnails") => Ok(THUMBNAILS_ATTR),
+            Some("ratings") => Ok(RATINGS_ATTR),
+            Some("flagged") => Ok(FLAGGED_ATTR),
+            Some("hidden") => Ok(HIDDEN_ATTR),
+            Some("by-date") => Ok(BY_DATE_ATTR),
+            Some("searches") if self.has_table("SavedSearchDBTable") => Ok(SEARCH_ATTR),
+            Some("people") if self.has_faces() => Ok(PERSON_ATTR),
+            Some("imports") => Ok(IMPORT_ATTR),
+            Some("last-import") => self.getattr_import(LAST_IMPORT),
+            Some("import") if self.writable => self.attr(IMPORT_DROP),
+            _ => Err(ENOENT),
+        }
+    }
+
+    fn lookup_thumbnail_sizes(&mut self, name: &OsStr) -> Result<FileAttr, c_int> {
+        match name.to_str() {
+            Some("128") => Ok(make_dirattr(THUMBNAILS | ROOT, NOTIME)),
+            Some("360") => Ok(make_dirattr(THUMBNAILS | THUMB_360 | ROOT, NOTIME)),
+            _ => Err(ENOENT),
+        }
+    }
+
+    /// Resolve `name` within a `thumbnails/` mirror directory.
+    ///
+    /// Media files are named after the original with a `.jpg` suffix, which is stripped before
+    /// looking the original up in the directory the mirror reflects.
+    fn lookup_thumbnails(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        let mirror = parent & MIRROR_MASK;
+        let base = parent & !mirror;
+        if base == ROOT && !["photos", "videos", "tags", "events"].iter().any(|dir| name.to_str() == Some(dir)) {
+            return Err(ENOENT);
+        }
+        if let Some(FileId::File(_)) = self.extract_id(name) {
+            let original = name.to_str().and_then(|name| name.strip_suffix(".jpg")).ok_or(ENOENT)?;
+            let attr = self.lookup_entry(base, OsStr::new(original))?;
+            return self.thumbnail_attr(attr.ino | mirror);
+        }
+        match self.lookup_entry(base, name)? {
+            attr if attr.kind == FileType::Directory => Ok(FileAttr { ino: attr.ino | mirror, ..attr }),
+            _ => Err(ENOENT),
+        }
+    }
+
+    fn lookup_ratings(&mut self, name: &OsStr) -> Result<FileAttr, c_int> {
+        RATING_DIRS.iter()
+                   .position(|&(dir, _)| name.to_str() == Some(dir))
+                   .map(|i| make_dirattr(RATINGS | (i as u64 + 1), NOTIME))
+                   .ok_or(ENOENT)
+    }
+
+    fn lookup_rating_contents(&mut self, inode: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        match RATING_DIRS.get(((inode & !RATINGS) as usize).wrapping_sub(1)) {
+            Some(&(_, filter)) => {
+                let filter = self.visible(filter);
+                self.lookup_media(name, &filter, &[])
+            },
+            None => Err(ENOENT),
+        }
+    }
+
+    fn lookup_date(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        if date_parts(parent).2 != 0 {
+            let params = [sqlite::Value::String(date_pattern(parent))];
+            let filter = self.visible(DATED_FILTER);
+            return self.lookup_media(name, &filter, &params);
+        }
+        if parent == BY_DATE && name.to_str() == Some("undated") {
+            return self.getattr_undated();
+        }
+        self.date_subdirs(parent).into_iter()
+                                 .find(|(dir, _)| name.to_str() == Some(&dir[..]))
+                                 .map(|(_, attr)| attr)
+                                 .ok_or(ENOENT)
+    }
+
+    fn lookup_search(&mut self, name: &OsStr) -> Result<FileAttr, c_int> {
+        match self.extract_id(name) {
+            Some(FileId::Dir(id)) => self.getattr_search(SEARCH | id),
+            _ => Err(ENOENT),
+        }
+    }
+
+    fn lookup_search_contents(&mut self, inode: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        let search = SavedSearch::load(&self.conn, inode & !SEARCH).ok_or(ENOENT)?;
+        let filter = self.visible(&search.filter());
+        self.lookup_media(name, &filter, search.params())
+    }
+
+    fn lookup_person(&mut self, name: &OsStr) -> Result<FileAttr, c_int> {
+        match self.extract_id(name) {
+            Some(FileId::Dir(id)) => self.getattr_person(PERSON | id),
+            _ => Err(ENOENT),
+        }
+    }
+
+    fn lookup_person_contents(&mut self, inode: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        let params = [sqlite::Value::Integer((inode & !PERSON) as i64)];
+        let filter = self.visible(PERSON_FILTER);
+        self.lookup_media(name, &filter, &params)
+    }
+
+    fn lookup_import(&mut self, name: &OsStr) -> Result<FileAttr, c_int> {
+        let import_id = self.import_rolls().into_iter()
+                                           .find(|(_, roll)| name.to_str() == Some(&roll[..]))
+                                           .map(|(import_id, _)| import_id)
+                                           .ok_or(ENOENT)?;
+        Ok(make_dirattr(IMPORT | import_id, time::Timespec{sec: import_id as i64, nsec: 0}))
+    }
+
+    fn lookup_import_contents(&mut self, inode: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        let import_id = match inode {
+            LAST_IMPORT => self.last_import().ok_or(ENOENT)?,
+            x => x & !IMPORT,
+        };
+        let params = [sqlite::Value::Integer(import_id as i64)];
+        let filter = self.visible(IMPORT_FILTER);
+        self.lookup_media(name, &filter, &params)
+    }
+
+    fn lookup_member(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        let member = self.media_members(parent).into_iter()
+                                               .find(|(_, member)| name.to_str() == Some(&member[..]))
+                                               .ok_or(ENOENT)?;
+        self.getattr_member(member.0)
+    }
+
+    fn lookup_event(&mut self, name: &OsStr) -> Result<FileAttr, c_int> {
+        if let Some(FileId::Dir(id)) = self.extract_id(name) {
+            let mut statement = self.conn.prepare("SELECT time_created FROM EventTable WHERE id = ?").unwrap();
+            statement.bind(1, id as i64).unwrap();
+            if let Ok(sqlite::State::Row) = statement.next() {
+                let timestamp = time::Timespec{sec: statement.read::<i64>(0).unwrap(), nsec: 0};
+                return Ok(make_dirattr(EVENT | id, timestamp));
+            }
+        }
+        // Events made by `mkdir` must also resolve by the plain name they were created under, as
+        // long as no other event shares it: event names need not be unique.
+        let mut statement = self.conn.prepare("SELECT id, time_created FROM EventTable WHERE name = ? LIMIT 2").unwrap();
+        statement.bind(1, name.to_str().ok_or(ENOENT)?).unwrap();
+        let mut found = None;
+        while let Ok(sqlite::State::Row) = statement.next() {
+            if found.is_some() {
+                return Err(ENOENT);
+            }
+            let timestamp = time::Timespec{sec: statement.read::<i64>(1).unwrap(), nsec: 0};
+            found = Some(make_dirattr(EVENT | statement.read_u64(0).unwrap(), timestamp));
+        }
+        found.ok_or(ENOENT)
+    }
+
+    fn lookup_event_contents(&mut self, inode: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        let params = [sqlite::Value::Integer((inode & !EVENT) as i64)];
+        let filter = self.visible("event_id = ?2");
+        self.lookup_media(name, &filter, &params)
+    }
+
+    /// Resolve `name` among photos and videos matching `filter` (see `media_query`).
+    ///
+    /// Photo and video ids overlap, so the full generated name is compared to tell them apart.
+    fn lookup_media(&mut self, name: &OsStr, filter: &str, params: &[sqlite::Value]) -> Result<FileAttr, c_int> {
+        if let Some(FileId::File(id)) = self.extract_id(name) {
+            let mut statement = self.conn.prepare(media_query(&format!("id = ?1 AND ({})", filter))).unwrap();
+            statement.bind(1, id as i64).unwrap();
+            for (i, param) in params.iter().enumerate() {
+                statement.bind(i + 2, param).unwrap();
+            }
+            while let Ok(sqlite::State::Row) = statement.next() {
+                let kind = if statement.read::<i64>(5).unwrap() == 0 { PHOTO } else { VIDEO };
+                let filename = statement.read_text(1).unwrap_or_default();
+                let title = statement.read_text(3).unwrap_or_default();
+                let ts = statement.read::<i64>(2).unwrap();
+                if self.is_media_name(name, &media_name(id, file_extension(&filename), ts, &title)) {
+                    let filesize = statement.read_u64(4).unwrap();
+                    return Ok(make_fileattr(id | kind, filesize, time::Timespec{sec: ts, nsec: 0}));
+                }
+            }
+        }
+        Err(ENOENT)
+    }
+
+    fn lookup_tag(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        match self.extract_id(name) {
+            Some(FileId::Dir(id)) => {
+                let mut statement = self.conn.prepare("SELECT name, time_created FROM TagTable WHERE id = ?").unwrap();
+                statement.bind(1, id as i64).unwrap();
+                if let Ok(sqlite::State::Row) = statement.next() {
+                    let tag_name = statement.read_text(0).unwrap_or_default();
+                    if self.tag_parent(&tag_name) == parent {
+                        let timestamp = time::Timespec{sec: statement.read::<i64>(1).unwrap(), nsec: 0};
+                        return Ok(make_dirattr(TAG | id, timestamp));
+                    }
+                }
+            },
+            Some(FileId::File(id)) if parent != TAG => {
+                for source in self.tag_sources(parent & !TAG) {
+                    if source != SourceId::Photo(id) && source != SourceId::Video(id) {
+                        continue;
+                    }
+                    if let Some(entry) = self.media_entry(source) {
+                        if self.is_media_name(name, &entry.name) {
+                            return Ok(make_fileattr(entry.inode, entry.filesize, entry.timestamp));
+                        }
+                    }
+                }
+            },
+            _ => (),
+        };
+        Err(ENOENT)
+    }
+
+    fn lookup_photo(&mut self, name: &OsStr) -> Result<FileAttr, c_int> {
+        if let Some(FileId::File(id)) = self.extract_id(name) {
+            let mut statement = self.conn.prepare(format!("SELECT filesize, timestamp FROM PhotoTable WHERE id = ? AND {}", self.visible_photo("flags"))).unwrap();
+            statement.bind(1, id as i64).unwrap();
+            if let Ok(sqlite::State::Row) = statement.next() {
+                let timestamp = time::Timespec{sec: statement.read::<i64>(1).unwrap(), nsec: 0};
+                let filesize = statement.read_u64(0).unwrap();
+                return Ok(make_fileattr(PHOTO | id, filesize, timestamp));
+            }
+        }
+        Err(ENOENT)
+    }
+
+    fn lookup_edited(&mut self, name: &OsStr) -> Result<FileAttr, c_int> {
+        if let Some(FileId::File(id)) = self.extract_id(name) {
+            let mut statement = self.conn.prepare(format!("{} WHERE p.id = ? AND {}", EDITED_QUERY, self.visible_photo("p.flags"))).unwrap();
+            statement.bind(1, id as i64).unwrap();
+            if let Ok(sqlite::State::Row) = statement.next() {
+                let timestamp = time::Timespec{sec: statement.read::<i64>(2).unwrap(), nsec: 0};
+                let filesize = statement.read_u64(4).unwrap();
+                return Ok(make_fileattr(EDITED | PHOTO | id, filesize, timestamp));
+            }
+        }
+        Err(ENOENT)
+    }
+
+    fn lookup_rendered(&mut self, name: &OsStr) -> Result<FileAttr, c_int> {
+        match self.extract_id(name) {
+            Some(FileId::File(id)) if self.photo_visible(id) => self.rendered_attr(id),
+            _ => Err(ENOENT),
+        }
+    }
+
+    fn lookup_video(&mut self, name: &OsStr) -> Result<FileAttr, c_int> {
+        if let Some(FileId::File(id)) = self.extract_id(name) {
+            let mut statement = self.conn.prepare("SELECT filesize, timestamp FROM VideoTable WHERE id = ?").unwrap();
+            statement.bind(1, id as i64).unwrap();
+            if let Ok(sqlite::State::Row) = statement.next() {
+                let timestamp = time::Timespec{sec: statement.read::<i64>(1).unwrap(), nsec: 0};
+                let filesize = statement.read_u64(0).unwrap();
+                return Ok(make_fileattr(VIDEO | id, filesize, timestamp));
+            }
+        }
+        Err(ENOENT)
+    }
+
+    fn lookup_entry(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        let attr = match parent {
+            THUMBNAILS => self.lookup_thumbnail_sizes(name),
+            x if x & THUMBNAILS == THUMBNAILS => self.lookup_thumbnails(x, name),
+            ROOT => self.lookup_root(name),
+            EVENT => self.lookup_event(name),
+            PHOTO => self.lookup_photo(name),
+            VIDEO => self.lookup_video(name),
+            EDITED => self.lookup_edited(name),
+            RENDERED => self.lookup_rendered(name),
+            RATINGS => self.lookup_ratings(name),
+            FLAGGED => {
+                let filter = self.visible("flagged");
+                self.lookup_media(name, &filter, &[])
+            },
+            HIDDEN => self.lookup_media(name, "hidden", &[]),
+            UNDATED => {
+                let filter = self.visible(UNDATED_FILTER);
+                self.lookup_media(name, &filter, &[])
+            },
+            SEARCH => self.lookup_search(name),
+            PERSON => self.lookup_person(name),
+            IMPORT => self.lookup_import(name),
+            LAST_IMPORT => self.lookup_import_contents(LAST_IMPORT, name),
+            IMPORT_DROP if self.writable => self.lookup_import_drop(name),
+            x if x & TAG == TAG => self.lookup_tag(x, name),
+            x if x & EVENT == EVENT => self.lookup_event_contents(x, name),
+            x if x & RATINGS == RATINGS => self.lookup_rating_contents(x, name),
+            x if x & BY_DATE == BY_DATE => self.lookup_date(x, name),
+            x if x & SEARCH == SEARCH => self.lookup_search_contents(x, name),
+            x if x & PERSON == PERSON => self.lookup_person_contents(x, name),
+            x if x & IMPORT == IMPORT => self.lookup_import_contents(x, name),
+            x if self.media_dirs && inode_source(x).is_some() => self.lookup_member(x, name),
+            _ => Err(ENOENT),
+        }?;
+        if inode_source(attr.ino).is_some() {
+            Ok(self.media_attr(attr))
+        } else if is_derived_file(attr.ino) {
+            Ok(self.link_attr(attr))
+        } else {
+            Ok(attr)
+        }
+    }
+
+    fn attr(&mut self, inode: u64) -> Result<FileAttr, c_int> {
+        match inode {
+            ROOT => Ok(ROOT_ATTR),
+            PHOTO => Ok(PHOTO_ATTR),
+            VIDEO => Ok(VIDEO_ATTR),
+            TAG => Ok(TAG_ATTR),
+            EVENT => Ok(EVENT_ATTR),
+            EDITED => Ok(EDITED_ATTR),
+            RENDERED => Ok(RENDERED_ATTR),
+            THUMBNAILS => Ok(THUMBNAILS_ATTR),
+            RATINGS => Ok(RATINGS_ATTR),
+            FLAGGED => Ok(FLAGGED_ATTR),
+            HIDDEN => Ok(HIDDEN_ATTR),
+            BY_DATE => Ok(BY_DATE_ATTR),
+            UNDATED => self.getattr_undated(),
+            SEARCH => Ok(SEARCH_ATTR),
+            PERSON => Ok(PERSON_ATTR),
+            IMPORT => Ok(IMPORT_ATTR),
+            LAST_IMPORT => self.getattr_import(LAST_IMPORT),
+            IMPORT_DROP if self.writable => Ok(FileAttr { perm: 0o755, ..make_dirattr(IMPORT_DROP, NOTIME) }),
+            x if is_upload(x) => self.upload_attr(x),
+            x if x & THUMBNAILS == THUMBNAILS => self.getattr_thumbnails(x),
+            x if x & TAG == TAG => self.getattr_tag(x),
+            x if x & EVENT == EVENT => self.getattr_event(x),
+            x if x & RATINGS == RATINGS && x & !RATINGS <= RATING_DIRS.len() as u64 => Ok(make_dirattr(x, NOTIME)),
+            x if x & BY_DATE == BY_DATE => self.getattr_date(x),
+            x if x & SEARCH == SEARCH => self.getattr_search(x),
+            x if x & PERSON == PERSON => self.getattr_person(x),
+            x if x & IMPORT == IMPORT => self.getattr_import(x),
+            x if x & EDITED == EDITED => self.getattr_edited(x).map(|attr| self.link_attr(attr)),
+            x if x & RENDERED == RENDERED => self.getattr_rendered(x).map(|attr| self.link_attr(attr)),
+            x if x & MEMBER_MASK != 0 && inode_source(x & !MEMBER_MASK).is_some() => self.getattr_member(x),
+            x if (self.media_dirs || self.symlinks) && inode_source(x).is_some() => {
+                let attr = self.getattr_media(x)?;
+                Ok(self.media_attr(attr))
+            },
+            x if x & PHOTO == PHOTO => self.getattr_photo(x),
+            x if x & VIDEO == VIDEO => self.getattr_video(x),
+            _ => Err(ENOENT),
+        }
+    }
+
+    fn readdir_listing(&mut self, inode: u64, reply: Listing, offset: i64) {
+        match inode {
+            ROOT => self.readdir_root(reply, offset),
+            PHOTO => self.readdir_photos(reply, offset),
+            VIDEO => self.readdir_videos(reply, offset),
+            TAG => self.readdir_tags(reply, offset),
+            EVENT => self.readdir_events(reply, offset),
+            EDITED => self.readdir_edited(reply, offset),
+            RENDERED => self.readdir_rendered(reply, offset),
+            RATINGS => self.readdir_ratings(reply, offset),
+            FLAGGED => {
+                let filter = self.visible("flagged");
+                self.readdir_media(FLAGGED, ROOT, &filter, &[], reply, offset)
+            },
+            HIDDEN => self.readdir_media(HIDDEN, ROOT, "hidden", &[], reply, offset),
+            UNDATED => {
+                let filter = self.visible(UNDATED_FILTER);
+                self.readdir_media(UNDATED, BY_DATE, &filter, &[], reply, offset)
+            },
+            SEARCH => self.readdir_searches(reply, offset),
+            PERSON => self.readdir_people(reply, offset),
+            IMPORT => self.readdir_imports(reply, offset),
+            LAST_IMPORT => self.readdir_import_contents(LAST_IMPORT, reply, offset),
+            IMPORT_DROP if self.writable => self.readdir_import_drop(reply, offset),
+            x if self.media_dirs && inode_source(x).is_some() => self.readdir_members(x, reply, offset),
+            x if x & TAG == TAG => self.readdir_tag_contents(inode, reply, offset),
+            x if x & EVENT == EVENT => self.readdir_event_contents(inode, reply, offset),
+            x if x & RATINGS == RATINGS => self.readdir_rating_contents(inode, reply, offset),
+            x if x & BY_DATE == BY_DATE => self.readdir_date(x, reply, offset),
+            x if x & SEARCH == SEARCH => self.readdir_search_contents(x, reply, offset),
+            x if x & PERSON == PERSON => self.readdir_person_contents(x, reply, offset),
+            x if x & IMPORT == IMPORT => self.readdir_import_contents(x, reply, offset),
+            _ => reply.error(ENOENT)
+        };
+    }
+}
+
+/// Handlers of the filesystem operations, run by `ShotwellVFS` on a worker or on the session thread.
+impl Library {
     fn lookup(&mut self,
-              _: &fuse::Request,
               parent: u64,
               name: &OsStr,
               reply: ReplyEntry,
               ) {
-        match parent {
-            ROOT => self.lookup_root(name, reply),
-            EVENT => self.lookup_event(name, reply),
-            PHOTO => self.lookup_photo(name, reply),
-            VIDEO => self.lookup_video(name, reply),
-            TAG => self.lookup_tag(name, reply),
-            x if x & TAG == TAG => self.lookup_tag(name, reply),
-            _ => reply.error(ENOENT),
+        match self.lookup_entry(parent, name) {
+            Ok(attr) => reply.entry(entry_ttl(attr.ino), &attr, 0),
+            Err(errno) => reply.error(errno),
         };
     }
 
     fn getattr(&mut self,
-               _: &fuse::Request,
                inode: u64,
                reply: ReplyAttr,
                ) {
-        match inode {
-            ROOT => reply.attr(&TTL, &ROOT_ATTR),
-            PHOTO => reply.attr(&TTL, &PHOTO_ATTR),
-            VIDEO => reply.attr(&TTL, &VIDEO_ATTR),
-            TAG => reply.attr(&TTL, &TAG_ATTR),
-            EVENT => reply.attr(&TTL, &EVENT_ATTR),
-            x if x & TAG == TAG => self.getattr_tag(x, reply),
-            x if x & PHOTO == PHOTO => self.getattr_photo(x, reply),
-            _ => reply.error(ENOENT),
+        match self.attr(inode) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(errno) => reply.error(errno),
         };
     }
 
     fn readdir(&mut self,
-               _: &fuse::Request,
                inode: u64,
                _fh: u64,
                offset: i64,
                reply: fuse::ReplyDirectory,
                ) {
         match inode {
-            ROOT => self.readdir_root(reply, offset),
-            PHOTO => self.readdir_photos(reply, offset),
-            VIDEO => self.readdir_videos(reply, offset),
-            TAG => self.readdir_tags(reply, offset),
-            EVENT => self.readdir_events(reply, offset),
-            x if x & TAG == TAG => self.readdir_tag_contents(inode, reply, offset),
-            _ => reply.error(ENOENT)
+            THUMBNAILS => self.readdir_thumbnail_sizes(Listing::new(reply, false, false), offset),
+            x if x & THUMBNAILS == THUMBNAILS => self.readdir_thumbnails(x, reply, offset),
+            x => self.readdir_listing(x, Listing::new(reply, self.media_dirs, self.symlinks), offset),
         };
     }
 
+    fn open(&mut self,
+            inode: u64,
+            flags: u32,
+            reply: ReplyOpen,
+            ) {
+        // The size of a rendered photo is only known once `open` rendered it.
+        let open_flags = if inode & RENDERED == RENDERED { FOPEN_DIRECT_IO } else { 0 };
+        match self.open_handle(inode, flags) {
+            Ok(fh) => reply.opened(fh, open_flags),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn readlink(&mut self,
+                inode: u64,
+                reply: fuse::ReplyData,
+                ) {
+        if !self.symlinks || (inode_source(inode).is_none() && !is_derived_file(inode)) {
+            reply.error(EINVAL);
+            return;
+        }
+        match self.backing_file(inode) {
+            Ok(path) => reply.data(path.as_os_str().as_bytes()),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
     fn read(&mut self,
-            _: &fuse::Request,
             inode: u64,
-            _fh: u64,
+            fh: u64,
             offset: i64,
             size: u32,
             reply: fuse::ReplyData,
             ) {
-        if inode == PHOTO || inode & PHOTO != PHOTO || offset < 0 || size == 0 {
-            debug!("invalid inode {}, replying with ENOENT", inode);
-            reply.error(ENOENT);
+        if offset < 0 || size == 0 {
+            debug!("invalid read request for inode {}, replying with EINVAL", inode);
+            reply.error(EINVAL);
+            return;
+        }
+        if inode & MEMBER_MASK == MEMBER_METADATA {
+            match self.member_metadata(inode) {
+                Ok(json) => {
+                    let start = (offset as usize).min(json.len());
+                    let end = (start + size as usize).min(json.len());
+                    reply.data(&json.as_bytes()[start..end]);
+                },
+                Err(errno) => reply.error(errno),
+            }
             return;
         }
+        let handle = self.shared.handles.lock().unwrap().get(&fh).cloned();
+        let data = match handle {
+            Some(file) => read_file(&file, offset as u64, size as usize),
+            None => match self.backing_file(inode) {
+                Ok(filename) => File::open(&filename).and_then(|file| read_file(&file, offset as u64, size as usize)),
+                Err(errno) => {
+                    debug!("no backing file for inode {}, replying with {}", inode, errno);
+                    reply.error(errno);
+                    return;
+                },
+            },
+        };
+        match data {
+            Ok(data) => {
+                debug!("replying with {} bytes", data.len());
+                reply.data(&data);
+            },
+            Err(e) => {
+                debug!("reading inode {} failed: {}", inode, e);
+                reply.error(io_errno(&e));
+            },
+        }
+    }
 
-        let photo_id = inode & !PHOTO;
-        let mut statement = self.conn.prepare("SELECT filename from PhotoTable WHERE id = ?").unwrap();
-        statement.bind(1, photo_id as i64).unwrap();
-        if let Ok(sqlite::State::Row) = statement.next() {
-            let filename = statement.read_text(0).unwrap();
-            debug!("Reading photo id {} from filename {}", photo_id, filename);
-            if let Ok(mut fd) = File::open(&filename) {
-                if let Ok(_) = fd.seek(SeekFrom::Start(offset as u64)) {
-                    let mut buf = vec![0u8; size as usize];
-                    if let Ok(bytes) = fd.take(size as u64).read(&mut buf) {
-                        debug!("replying with {} bytes", bytes);
-                        reply.data(&buf[..bytes]);
-                    } else {
-                        debug!("no data, replying with EINVAL");
-                        reply.error(EINVAL);
-                    }
-                } else {
-                    debug!("seek failed, replying with EINVAL");
-                    reply.error(EINVAL);
+    fn getxattr(&mut self,
+                inode: u64,
+                name: &OsStr,
+                size: u32,
+                reply: ReplyXattr,
+                ) {
+        // Spare loading the metadata for the ACL and security attributes `ls` asks about.
+        if !name.to_str().is_some_and(is_metadata_xattr) {
+            reply.error(ENODATA);
+            return;
+        }
+        match self.xattrs(inode) {
+            Ok(xattrs) => match xattrs.iter().find(|&(xattr, _)| name == OsStr::new(xattr)) {
+                Some((_, value)) => reply_xattr(reply, value.as_bytes(), size),
+                None => reply.error(ENODATA),
+            },
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn listxattr(&mut self,
+                 inode: u64,
+                 size: u32,
+                 reply: ReplyXattr,
+                 ) {
+        match self.xattrs(inode) {
+            Ok(xattrs) => {
+                let mut names = Vec::new();
+                for (name, _) in xattrs {
+                    names.extend_from_slice(name.as_bytes());
+                    names.push(0);
                 }
-            } else {
-                debug!("file open failed, replying with EINVAL");
-                reply.error(EINVAL);
-            }
+                reply_xattr(reply, &names, size);
+            },
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn setxattr(&mut self,
+                inode: u64,
+                name: &OsStr,
+                value: &[u8],
+                flags: u32,
+                _position: u32,
+                reply: ReplyEmpty,
+                ) {
+        match self.set_xattr(inode, name, value, flags) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn removexattr(&mut self,
+                   inode: u64,
+                   name: &OsStr,
+                   reply: ReplyEmpty,
+                   ) {
+        match self.remove_xattr(inode, name) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn link(&mut self,
+            inode: u64,
+            newparent: u64,
+            newname: &OsStr,
+            reply: ReplyEntry,
+            ) {
+        match self.link_entry(inode, newparent, newname) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn unlink(&mut self,
+              parent: u64,
+              name: &OsStr,
+              reply: ReplyEmpty,
+              ) {
+        match self.unlink_entry(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn mkdir(&mut self,
+             parent: u64,
+             name: &OsStr,
+             _mode: u32,
+             reply: ReplyEntry,
+             ) {
+        match self.mkdir_entry(parent, name) {
+            Ok(attr) => reply.entry(entry_ttl(attr.ino), &attr, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn rmdir(&mut self,
+             parent: u64,
+             name: &OsStr,
+             reply: ReplyEmpty,
+             ) {
+        match self.rmdir_entry(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn rename(&mut self,
+              parent: u64,
+              name: &OsStr,
+              newparent: u64,
+              newname: &OsStr,
+              reply: ReplyEmpty,
+              ) {
+        match self.rename_entry(parent, name, newparent, newname) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn create(&mut self,
+              parent: u64,
+              name: &OsStr,
+              _mode: u32,
+              flags: u32,
+              reply: ReplyCreate,
+              ) {
+        match self.create_upload(parent, name) {
+            Ok((attr, fh)) => reply.created(&TTL, &attr, 0, fh, flags),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn write(&mut self,
+             inode: u64,
+             _fh: u64,
+             offset: i64,
+             data: &[u8],
+             _flags: u32,
+             reply: ReplyWrite,
+             ) {
+        match self.write_upload(inode, offset, data) {
+            Ok(written) => reply.written(written),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn setattr(&mut self, inode: u64, size: Option<u64>, reply: ReplyAttr) {
+        match self.set_upload_attr(inode, size) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Close the handle `fh` of `inode`, returning the upload it finishes, which is left for the
+    /// caller to import.
+    fn release(&mut self, inode: u64, fh: u64) -> Option<Upload> {
+        self.shared.handles.lock().unwrap().remove(&fh);
+        if is_upload(inode) { self.finish_upload(inode, fh) } else { None }
+    }
+}
+
+/// Filesystem session over a Shotwell library.
+///
+/// Lookups, listings, attributes and reads are answered by a pool of workers, each with its own
+/// read-only database connection, so one slow read does not hold up other requests. Changes are
+/// few and must not race each other, so they are made on the session thread, except for imports:
+/// hashing a large video would stall the session, so they run on a writable worker of their own.
+pub struct ShotwellVFS {
+    library: Library,
+    pool: Pool<Library>,
+    importer: Option<Pool<Library>>,
+}
+
+impl ShotwellVFS {
+    pub fn new<T: AsRef<Path>>(path: T, options: Options) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let shared = Arc::new(Shared::default());
+        let library = Library::connect(&path, &options, shared.clone(), !options.writable);
+        let importer = if options.writable {
+            let (path, options, shared) = (path.clone(), options.clone(), shared.clone());
+            Some(Pool::new(1, move || Library::connect(&path, &options, shared.clone(), false)))
         } else {
-            debug!("database lookup failed, replying with ENOENT");
-            reply.error(ENOENT);
+            None
+        };
+        let threads = options.threads;
+        let pool = Pool::new(threads, move || Library::connect(&path, &options, shared.clone(), true));
+        ShotwellVFS {
+            library,
+            pool,
+            importer,
+        }
+    }
+}
+
+impl Filesystem for ShotwellVFS {
+    fn lookup(&mut self, _: &fuse::Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let name = name.to_os_string();
+        self.pool.execute(move |library| library.lookup(parent, &name, reply));
+    }
+
+    fn getattr(&mut self, _: &fuse::Request, inode: u64, reply: ReplyAttr) {
+        self.pool.execute(move |library| library.getattr(inode, reply));
+    }
+
+    fn readdir(&mut self, _: &fuse::Request, inode: u64, fh: u64, offset: i64, reply: fuse::ReplyDirectory) {
+        self.pool.execute(move |library| library.readdir(inode, fh, offset, reply));
+    }
+
+    fn open(&mut self, _: &fuse::Request, inode: u64, flags: u32, reply: ReplyOpen) {
+        self.pool.execute(move |library| library.open(inode, flags, reply));
+    }
+
+    fn readlink(&mut self, _: &fuse::Request, inode: u64, reply: fuse::ReplyData) {
+        self.pool.execute(move |library| library.readlink(inode, reply));
+    }
+
+    fn read(&mut self, _: &fuse::Request, inode: u64, fh: u64, offset: i64, size: u32, reply: fuse::ReplyData) {
+        self.pool.execute(move |library| library.read(inode, fh, offset, size, reply));
+    }
+
+    fn getxattr(&mut self, _: &fuse::Request, inode: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let name = name.to_os_string();
+        self.pool.execute(move |library| library.getxattr(inode, &name, size, reply));
+    }
+
+    fn listxattr(&mut self, _: &fuse::Request, inode: u64, size: u32, reply: ReplyXattr) {
+        self.pool.execute(move |library| library.listxattr(inode, size, reply));
+    }
+
+    fn setxattr(&mut self, _: &fuse::Request, inode: u64, name: &OsStr, value: &[u8], flags: u32, position: u32, reply: ReplyEmpty) {
+        self.library.setxattr(inode, name, value, flags, position, reply);
+    }
+
+    fn removexattr(&mut self, _: &fuse::Request, inode: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.library.removexattr(inode, name, reply);
+    }
+
+    fn link(&mut self, _: &fuse::Request, inode: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        self.library.link(inode, newparent, newname, reply);
+    }
+
+    fn unlink(&mut self, _: &fuse::Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.library.unlink(parent, name, reply);
+    }
+
+    fn mkdir(&mut self, _: &fuse::Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        self.library.mkdir(parent, name, mode, reply);
+    }
+
+    fn rmdir(&mut self, _: &fuse::Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.library.rmdir(parent, name, reply);
+    }
+
+    fn rename(&mut self, _: &fuse::Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, reply: ReplyEmpty) {
+        self.library.rename(parent, name, newparent, newname, reply);
+    }
+
+    fn create(&mut self, _: &fuse::Request, parent: u64, name: &OsStr, mode: u32, flags: u32, reply: ReplyCreate) {
+        self.library.create(parent, name, mode, flags, reply);
+    }
+
+    fn write(&mut self, _: &fuse::Request, inode: u64, fh: u64, offset: i64, data: &[u8], flags: u32, reply: ReplyWrite) {
+        self.library.write(inode, fh, offset, data, flags, reply);
+    }
+
+    fn setattr(&mut self,
+               _: &fuse::Request,
+               inode: u64,
+               _mode: Option<u32>,
+               _uid: Option<u32>,
+               _gid: Option<u32>,
+               size: Option<u64>,
+               _atime: Option<Timespec>,
+               _mtime: Option<Timespec>,
+               _fh: Option<u64>,
+               _crtime: Option<Timespec>,
+               _chgtime: Option<Timespec>,
+               _bkuptime: Option<Timespec>,
+               _flags: Option<u32>,
+               reply: ReplyAttr,
+               ) {
+        self.library.setattr(inode, size, reply);
+    }
+
+    fn release(&mut self, _: &fuse::Request, inode: u64, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
+        if let (Some(upload), Some(importer)) = (self.library.release(inode, fh), self.importer.as_ref()) {
+            importer.execute(move |library| if let Err(errno) = library.import_upload(upload) {
+                debug!("import of inode {} failed with {}", inode, errno);
+            });
         }
+        reply.ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::libc::EINVAL;
+    use super::{date_parent, date_parts, date_pattern, dir_title, BY_DATE, MONTH_SHIFT, ROOT, YEAR_SHIFT};
+
+    #[test]
+    fn dates() {
+        let day = BY_DATE | 2023 << YEAR_SHIFT | 7 << MONTH_SHIFT | 4;
+        let month = BY_DATE | 2023 << YEAR_SHIFT | 7 << MONTH_SHIFT;
+        let year = BY_DATE | 2023 << YEAR_SHIFT;
+        assert_eq!(date_parts(day), (2023, 7, 4));
+        assert_eq!(date_parts(month), (2023, 7, 0));
+        assert_eq!(date_parts(BY_DATE), (0, 0, 0));
+
+        assert_eq!(date_pattern(BY_DATE), "%");
+        assert_eq!(date_pattern(year), "2023-%");
+        assert_eq!(date_pattern(month), "2023-07-%");
+        assert_eq!(date_pattern(day), "2023-07-04");
+
+        assert_eq!(date_parent(day), month);
+        assert_eq!(date_parent(month), year);
+        assert_eq!(date_parent(year), BY_DATE);
+        assert_eq!(date_parent(BY_DATE), ROOT);
+    }
+
+    #[test]
+    fn dir_titles() {
+        assert_eq!(dir_title(OsStr::new("Beach")), Ok("Beach"));
+        assert_eq!(dir_title(OsStr::new("[12] Beach")), Ok("Beach"));
+        assert_eq!(dir_title(OsStr::new("[12] [3] Beach")), Ok("[3] Beach"));
+        assert_eq!(dir_title(OsStr::new("[x] Beach")), Ok("[x] Beach"));
+        assert_eq!(dir_title(OsStr::new("[12]Beach")), Ok("[12]Beach"));
+        assert_eq!(dir_title(OsStr::new("[12] ")), Err(EINVAL));
+        assert_eq!(dir_title(OsStr::new("")), Err(EINVAL));
+        assert_eq!(dir_title(OsStr::from_bytes(b"\xff")), Err(EINVAL));
     }
 }
@@ -0,0 +1,180 @@
+extern crate exif;
+extern crate image;
+extern crate md5;
+extern crate time;
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use metadata::mime_type;
+
+/// Shotwell's `PhotoFileFormat` for the photo formats that can be imported, by MIME type.
+const PHOTO_FORMATS: [(&str, i64); 5] = [
+    ("image/jpeg", 0),
+    ("image/png", 2),
+    ("image/tiff", 3),
+    ("image/bmp", 4),
+    ("image/webp", 6),
+];
+
+/// Kind of media a dropped file is imported as, judged by its name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    /// A photo with its `PhotoTable.file_format`.
+    Photo(i64),
+    Video,
+}
+
+impl MediaKind {
+    /// Kind of the file `name`, or `None` when it is neither a supported photo nor a video.
+    pub fn of(name: &str) -> Option<MediaKind> {
+        let mime = mime_type(name);
+        if mime.starts_with("video/") {
+            return Some(MediaKind::Video);
+        }
+        PHOTO_FORMATS.iter().find(|&&(format, _)| format == mime).map(|&(_, format)| MediaKind::Photo(format))
+    }
+}
+
+/// What is read from a dropped file before it is added to the library.
+pub struct Probe {
+    pub md5: String,
+    pub filesize: u64,
+    pub width: i64,
+    pub height: i64,
+    /// Local time the photo was taken according to its EXIF data, 0 when unknown.
+    pub exposure_time: i64,
+    /// EXIF orientation, 1 when unknown.
+    pub orientation: i64,
+}
+
+fn file_md5(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = md5::Context::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => hasher.consume(&buf[..n]),
+        }
+    }
+    Ok(format!("{:x}", hasher.compute()))
+}
+
+/// Exposure time and orientation from the EXIF data of `path`.
+fn read_exif(path: &Path) -> Option<(i64, i64)> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let data = exif::Reader::new().read_from_container(&mut reader).ok()?;
+    let orientation = data.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+                          .and_then(|field| field.value.get_uint(0))
+                          .map(|orientation| orientation as i64)
+                          .unwrap_or(1);
+    let exposure_time = [exif::Tag::DateTimeOriginal, exif::Tag::DateTimeDigitized, exif::Tag::DateTime].iter()
+        .filter_map(|&tag| data.get_field(tag, exif::In::PRIMARY))
+        .filter_map(|field| match field.value {
+            exif::Value::Ascii(ref values) if !values.is_empty() => exif::DateTime::from_ascii(&values[0]).ok(),
+            _ => None,
+        })
+        .filter_map(|date| {
+            let text = format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", date.year, date.month, date.day, date.hour, date.minute, date.second);
+            time::strptime(&text, "%Y-%m-%d %H:%M:%S").ok()
+        })
+        // EXIF times are local and carry no zone, so take the local offset at that moment.
+        .map(|tm| {
+            let utc = tm.to_timespec().sec;
+            utc - time::at(time::Timespec{sec: utc, nsec: 0}).tm_utcoff as i64
+        })
+        .next()
+        .unwrap_or(0);
+    Some((exposure_time, orientation))
+}
+
+/// Hash `path` and read its dimensions and EXIF data; dimensions of videos are left at 0.
+pub fn probe(path: &Path, kind: MediaKind) -> io::Result<Probe> {
+    let filesize = fs::metadata(path)?.len();
+    let (width, height) = match kind {
+        MediaKind::Photo(_) => image::image_dimensions(path).map(|(width, height)| (width as i64, height as i64)).unwrap_or((0, 0)),
+        MediaKind::Video => (0, 0),
+    };
+    let (exposure_time, orientation) = match kind {
+        MediaKind::Photo(_) => read_exif(path).unwrap_or((0, 1)),
+        MediaKind::Video => (0, 1),
+    };
+    Ok(Probe {
+        md5: file_md5(path)?,
+        filesize,
+        width,
+        height,
+        exposure_time,
+        orientation,
+    })
+}
+
+/// Move the file `from` into the `YYYY/MM/DD` directory of `library` for local time `timestamp`,
+/// the layout Shotwell copies imports into, as `name` or, when that is taken, with a `_N` suffix
+/// before the extension. Files already there are never replaced. Returns the new path.
+pub fn move_to_library(from: &Path, library: &Path, timestamp: i64, name: &str) -> io::Result<PathBuf> {
+    let date = time::at(time::Timespec{sec: timestamp, nsec: 0});
+    let dir = library.join(date.strftime("%Y/%m/%d").unwrap().to_string());
+    fs::create_dir_all(&dir)?;
+    let (stem, extension) = match name.rfind('.') {
+        Some(pos) if pos > 0 => (&name[..pos], &name[pos..]),
+        _ => (name, ""),
+    };
+    let mut path = dir.join(name);
+    let mut n = 1;
+    // Linking fails rather than replace a file that appeared since the name was tried.
+    loop {
+        match fs::hard_link(from, &path) {
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                path = dir.join(format!("{}_{}{}", stem, n, extension));
+                n += 1;
+            },
+            result => break result?,
+        }
+    }
+    fs::remove_file(from)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{move_to_library, MediaKind};
+
+    #[test]
+    fn media_kinds() {
+        assert_eq!(MediaKind::of("beach.JPG"), Some(MediaKind::Photo(0)));
+        assert_eq!(MediaKind::of("scan.tiff"), Some(MediaKind::Photo(3)));
+        assert_eq!(MediaKind::of("clip.mov"), Some(MediaKind::Video));
+        assert_eq!(MediaKind::of("raw.cr2"), None);
+        assert_eq!(MediaKind::of("notes.txt"), None);
+        assert_eq!(MediaKind::of("jpg"), None);
+    }
+
+    #[test]
+    fn move_without_clobbering() {
+        let dir = std::env::temp_dir().join(format!("shotwellvfs-import-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let timestamp = 1_500_000_000;
+        let date = time::at(time::Timespec{sec: timestamp, nsec: 0}).strftime("%Y/%m/%d").unwrap().to_string();
+
+        let mut moved = Vec::new();
+        for content in &["first", "second", "third"] {
+            let part = dir.join(".1.beach.jpg.part");
+            fs::write(&part, content).unwrap();
+            moved.push(move_to_library(&part, &dir, timestamp, "beach.jpg").unwrap());
+            assert!(!part.exists());
+        }
+        assert_eq!(moved, vec![dir.join(&date).join("beach.jpg"), dir.join(&date).join("beach_1.jpg"), dir.join(&date).join("beach_2.jpg")]);
+        assert_eq!(fs::read(&moved[0]).unwrap(), b"first");
+        assert_eq!(fs::read(&moved[2]).unwrap(), b"third");
+
+        let part = dir.join("README.part");
+        fs::write(&part, "text").unwrap();
+        assert_eq!(move_to_library(&part, &dir, timestamp, "README").unwrap(), dir.join(&date).join("README"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -4,9 +4,20 @@ extern crate env_logger;
 #[macro_use] extern crate clap;
 
 mod sqlite_ex;
+mod source_id;
+mod color;
+mod render;
+mod thumbnails;
+mod search;
+mod metadata;
+mod trash;
+mod import;
+mod pool;
 mod fuse_interface;
 
-use fuse_interface::ShotwellVFS;
+use std::path::PathBuf;
+
+use fuse_interface::{Options, ShotwellVFS};
 
 fn main() {
     env_logger::init().unwrap();
@@ -20,6 +31,48 @@ fn main() {
              .help("Custom path to database file")
              .takes_value(true)
             )
+        .arg(clap::Arg::with_name("thumbnails")
+             .long("thumbnails")
+             .value_name("DIR")
+             .help("Custom path to Shotwell thumbnail cache directory")
+             .takes_value(true)
+            )
+        .arg(clap::Arg::with_name("render-cache")
+             .long("render-cache")
+             .value_name("DIR")
+             .help("Custom path to the directory where rendered photos are cached")
+             .takes_value(true)
+            )
+        .arg(clap::Arg::with_name("hide-hidden")
+             .long("hide-hidden")
+             .help("Show hidden photos only in the hidden/ directory")
+            )
+        .arg(clap::Arg::with_name("media-dirs")
+             .long("media-dirs")
+             .help("Show every photo and video as a directory with its original, edited version, thumbnails and metadata")
+            )
+        .arg(clap::Arg::with_name("symlinks")
+             .long("symlinks")
+             .conflicts_with("media-dirs")
+             .help("Show every photo and video as a symbolic link to its original file")
+            )
+        .arg(clap::Arg::with_name("writable")
+             .long("writable")
+             .help("Allow writing ratings, titles and comments back to the database")
+            )
+        .arg(clap::Arg::with_name("library")
+             .long("library")
+             .value_name("DIR")
+             .help("Directory where files copied into import/ are stored, ~/Pictures by default")
+             .takes_value(true)
+            )
+        .arg(clap::Arg::with_name("threads")
+             .long("threads")
+             .value_name("N")
+             .help("Number of worker threads answering requests")
+             .default_value("4")
+             .takes_value(true)
+            )
         .arg(clap::Arg::with_name("MOUNTPOINT")
              .help("Path to mount FS")
              .required(true)
@@ -28,13 +81,35 @@ fn main() {
         .get_matches();
 
     let mountpoint = args.value_of("MOUNTPOINT").unwrap();
+    let home_dir = |default: &str| std::env::home_dir().unwrap_or_else(|| panic!("Cannot find user home dir for the default {}", default));
+    let cache_dir = |name: &str, default: &str| {
+        let mut path = std::env::var_os("XDG_CACHE_HOME").map(PathBuf::from).unwrap_or_else(|| home_dir(default).join(".cache"));
+        path.push(name);
+        path
+    };
+    let data_dir = |name: &str, default: &str| {
+        let mut path = std::env::var_os("XDG_DATA_HOME").map(PathBuf::from).unwrap_or_else(|| home_dir(default).join(".local/share"));
+        path.push(name);
+        path
+    };
+    let options = Options {
+        render_cache: args.value_of("render-cache").map(PathBuf::from).unwrap_or_else(|| cache_dir("shotwellvfs/rendered", "--render-cache directory")),
+        thumbnails: args.value_of("thumbnails").map(PathBuf::from).unwrap_or_else(|| cache_dir("shotwell/thumbs", "--thumbnails directory")),
+        hide_hidden: args.is_present("hide-hidden"),
+        media_dirs: args.is_present("media-dirs"),
+        symlinks: args.is_present("symlinks"),
+        writable: args.is_present("writable"),
+        trash: data_dir("Trash", "trash directory"),
+        threads: value_t_or_exit!(args, "threads", usize),
+        library: args.value_of("library").map(PathBuf::from).unwrap_or_else(|| home_dir("--library directory").join("Pictures")),
+    };
     let vfs = match args.value_of("db") {
         None => {
-            let mut path = std::env::home_dir().unwrap_or_else(|| panic!("Cannot find user home dir and no --db argument specified"));
+            let mut path = home_dir("--db path");
             path.push(".local/share/shotwell/data/photo.db");
-            ShotwellVFS::new(path)
+            ShotwellVFS::new(path, options)
         },
-        Some(path) => ShotwellVFS::new(path),
+        Some(path) => ShotwellVFS::new(path, options),
     };
     fuse::mount(vfs, &mountpoint, &[]).unwrap();
 }
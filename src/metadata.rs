@@ -0,0 +1,245 @@
+extern crate sqlite;
+
+use sqlite_ex::{TextField, UnsignedField};
+use source_id::SourceId;
+
+/// Shotwell's description of a photo or video, gathered from its row, its event and its tags.
+pub struct Metadata {
+    pub source: SourceId,
+    pub title: String,
+    pub comment: String,
+    pub filename: String,
+    pub edited_filename: Option<String>,
+    pub filesize: u64,
+    pub width: i64,
+    pub height: i64,
+    pub exposure_time: i64,
+    pub timestamp: i64,
+    pub import_id: i64,
+    pub rating: i64,
+    pub md5: String,
+    pub event: Option<(u64, String)>,
+    pub tags: Vec<String>,
+}
+
+/// Prefix of the extended attributes carrying Shotwell's metadata.
+pub const XATTR_PREFIX: &str = "user.shotwell.";
+
+/// Extended attribute carrying the MIME type, as understood by desktop file managers.
+pub const MIME_TYPE_XATTR: &str = "user.mime_type";
+
+/// Whether `name` is one of the extended attributes `Metadata::xattrs` may produce.
+pub fn is_metadata_xattr(name: &str) -> bool {
+    name.starts_with(XATTR_PREFIX) || name == MIME_TYPE_XATTR
+}
+
+/// MIME type of a photo or video by the extension of its file name.
+pub fn mime_type(filename: &str) -> &'static str {
+    let extension = filename.rfind('.').map(|x| filename[x+1..].to_lowercase()).unwrap_or_default();
+    match &extension[..] {
+        "jpg" | "jpeg" | "jpe" => "image/jpeg",
+        "png" => "image/png",
+        "tif" | "tiff" => "image/tiff",
+        "bmp" => "image/bmp",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "heic" | "heif" => "image/heif",
+        "cr2" => "image/x-canon-cr2",
+        "crw" => "image/x-canon-crw",
+        "nef" => "image/x-nikon-nef",
+        "dng" => "image/x-adobe-dng",
+        "arw" => "image/x-sony-arw",
+        "orf" => "image/x-olympus-orf",
+        "pef" => "image/x-pentax-pef",
+        "raf" => "image/x-fuji-raf",
+        "rw2" => "image/x-panasonic-rw2",
+        "mp4" | "m4v" => "video/mp4",
+        "mov" => "video/quicktime",
+        "avi" => "video/x-msvideo",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "ogv" | "ogg" => "video/ogg",
+        "mpg" | "mpeg" => "video/mpeg",
+        "mts" | "m2ts" => "video/mp2t",
+        "3gp" => "video/3gpp",
+        "wmv" => "video/x-ms-wmv",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Quote `text` as a JSON string.
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl Metadata {
+    pub fn load(conn: &sqlite::Connection, source: SourceId) -> Option<Metadata> {
+        let (query, id) = match source {
+            SourceId::Photo(id) => ("SELECT p.title, p.comment, p.filename, b.filepath, p.filesize, p.width, p.height, p.exposure_time, \
+                                            p.timestamp, p.import_id, p.rating, p.md5, p.event_id \
+                                     FROM PhotoTable p LEFT JOIN BackingPhotoTable b ON b.id = p.editable_id WHERE p.id = ?", id),
+            SourceId::Video(id) => ("SELECT title, comment, filename, NULL, filesize, width, height, exposure_time, \
+                                            timestamp, import_id, rating, md5, event_id \
+                                     FROM VideoTable WHERE id = ?", id),
+        };
+        let mut statement = conn.prepare(query).unwrap();
+        statement.bind(1, id as i64).unwrap();
+        match statement.next() {
+            Ok(sqlite::State::Row) => (),
+            _ => return None,
+        }
+        let event_id = statement.read::<i64>(12).unwrap();
+        let mut metadata = Metadata {
+            source,
+            title: statement.read_text(0).unwrap_or_default(),
+            comment: statement.read_text(1).unwrap_or_default(),
+            filename: statement.read_text(2).unwrap_or_default(),
+            edited_filename: statement.read_text(3).filter(|edited| !edited.is_empty()),
+            filesize: statement.read_u64(4).unwrap(),
+            width: statement.read::<i64>(5).unwrap(),
+            height: statement.read::<i64>(6).unwrap(),
+            exposure_time: statement.read::<i64>(7).unwrap(),
+            timestamp: statement.read::<i64>(8).unwrap(),
+            import_id: statement.read::<i64>(9).unwrap(),
+            rating: statement.read::<i64>(10).unwrap(),
+            md5: statement.read_text(11).unwrap_or_default(),
+            event: None,
+            tags: Vec::new(),
+        };
+
+        let mut statement = conn.prepare("SELECT name FROM EventTable WHERE id = ?").unwrap();
+        statement.bind(1, event_id).unwrap();
+        if let Ok(sqlite::State::Row) = statement.next() {
+            metadata.event = Some((event_id as u64, statement.read_text(0).unwrap_or_default()));
+        }
+
+        let mut statement = conn.prepare("SELECT name FROM TagTable WHERE ',' || photo_id_list || ',' LIKE ? ORDER BY name ASC").unwrap();
+        statement.bind(1, &format!("%,{},%", source)[..]).unwrap();
+        while let Ok(sqlite::State::Row) = statement.next() {
+            metadata.tags.extend(statement.read_text(0));
+        }
+        Some(metadata)
+    }
+
+    pub fn to_json(&self) -> String {
+        let (kind, id) = match self.source {
+            SourceId::Photo(id) => ("photo", id),
+            SourceId::Video(id) => ("video", id),
+        };
+        let edited_filename = self.edited_filename.as_deref().map(json_string).unwrap_or_else(|| "null".to_owned());
+        let event = match self.event {
+            Some((id, ref name)) => format!("{{\"id\": {}, \"name\": {}}}", id, json_string(name)),
+            None => "null".to_owned(),
+        };
+        let tags: Vec<String> = self.tags.iter().map(|tag| json_string(tag)).collect();
+        format!("{{\n  \"id\": {},\n  \"kind\": \"{}\",\n  \"title\": {},\n  \"comment\": {},\n  \"filename\": {},\n  \
+                 \"edited_filename\": {},\n  \"filesize\": {},\n  \"width\": {},\n  \"height\": {},\n  \"exposure_time\": {},\n  \
+                 \"timestamp\": {},\n  \"import_id\": {},\n  \"rating\": {},\n  \"md5\": {},\n  \"event\": {},\n  \"tags\": [{}]\n}}\n",
+                id, kind, json_string(&self.title), json_string(&self.comment), json_string(&self.filename),
+                edited_filename, self.filesize, self.width, self.height, self.exposure_time,
+                self.timestamp, self.import_id, self.rating, json_string(&self.md5), event, tags.join(", "))
+    }
+
+    /// Extended attributes as `(name, value)` pairs. Empty title, comment and tags and a missing
+    /// event are left out; tags are given by full name, one per line.
+    pub fn xattrs(&self) -> Vec<(String, String)> {
+        let mut xattrs = Vec::new();
+        {
+            let mut add = |name: &str, value: String| xattrs.push((format!("{}{}", XATTR_PREFIX, name), value));
+            if !self.title.is_empty() {
+                add("title", self.title.clone());
+            }
+            if !self.comment.is_empty() {
+                add("comment", self.comment.clone());
+            }
+            add("rating", self.rating.to_string());
+            if !self.tags.is_empty() {
+                add("tags", self.tags.join("\n"));
+            }
+            if let Some((_, ref name)) = self.event {
+                add("event", name.clone());
+            }
+            add("md5", self.md5.clone());
+            add("original_path", self.filename.clone());
+            add("width", self.width.to_string());
+            add("height", self.height.to_string());
+            add("exposure_time", self.exposure_time.to_string());
+        }
+        xattrs.push((MIME_TYPE_XATTR.to_owned(), mime_type(&self.filename).to_owned()));
+        xattrs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use source_id::SourceId;
+
+    use super::{json_string, mime_type, Metadata};
+
+    #[test]
+    fn mime_types() {
+        assert_eq!(mime_type("IMG_0001.JPG"), "image/jpeg");
+        assert_eq!(mime_type("holiday.photo.png"), "image/png");
+        assert_eq!(mime_type("DSC_0002.nef"), "image/x-nikon-nef");
+        assert_eq!(mime_type("clip.MTS"), "video/mp2t");
+        assert_eq!(mime_type("archive.tar.gz"), "application/octet-stream");
+        assert_eq!(mime_type("no_extension"), "application/octet-stream");
+    }
+
+    #[test]
+    fn json_escaping() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("say \"hi\"\\"), "\"say \\\"hi\\\"\\\\\"");
+        assert_eq!(json_string("a\nb\tc\r"), "\"a\\nb\\tc\\r\"");
+        assert_eq!(json_string("\u{1}é"), "\"\\u0001é\"");
+    }
+
+    #[test]
+    fn json_document() {
+        let mut metadata = Metadata {
+            source: SourceId::Photo(7),
+            title: "Beach \"day\"".to_owned(),
+            comment: String::new(),
+            filename: "/photos/IMG_0007.jpg".to_owned(),
+            edited_filename: None,
+            filesize: 1024,
+            width: 640,
+            height: 480,
+            exposure_time: 1623758400,
+            timestamp: 1623760000,
+            import_id: 1623770000,
+            rating: 4,
+            md5: "abc".to_owned(),
+            event: Some((3, "Summer".to_owned())),
+            tags: vec!["/Holidays".to_owned(), "/Holidays/Beach".to_owned()],
+        };
+        assert_eq!(metadata.to_json(), "{\n  \"id\": 7,\n  \"kind\": \"photo\",\n  \"title\": \"Beach \\\"day\\\"\",\n  \
+                                        \"comment\": \"\",\n  \"filename\": \"/photos/IMG_0007.jpg\",\n  \"edited_filename\": null,\n  \
+                                        \"filesize\": 1024,\n  \"width\": 640,\n  \"height\": 480,\n  \"exposure_time\": 1623758400,\n  \
+                                        \"timestamp\": 1623760000,\n  \"import_id\": 1623770000,\n  \"rating\": 4,\n  \"md5\": \"abc\",\n  \
+                                        \"event\": {\"id\": 3, \"name\": \"Summer\"},\n  \"tags\": [\"/Holidays\", \"/Holidays/Beach\"]\n}\n");
+
+        metadata.source = SourceId::Video(7);
+        metadata.edited_filename = Some("/edited/IMG_0007.jpg".to_owned());
+        metadata.event = None;
+        metadata.tags.clear();
+        let json = metadata.to_json();
+        assert!(json.contains("\"kind\": \"video\",\n"));
+        assert!(json.contains("\"edited_filename\": \"/edited/IMG_0007.jpg\",\n"));
+        assert!(json.contains("\"event\": null,\n  \"tags\": []\n}\n"));
+    }
+}
@@ -0,0 +1,120 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+type Job<T> = Box<dyn FnOnce(&mut T) + Send>;
+
+/// Delay before a worker whose state could not be built tries again.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Attempts a worker makes at building its state in a row before it gives up.
+const MAX_INIT_ATTEMPTS: usize = 5;
+
+/// Fixed set of worker threads running jobs against state each worker builds for itself, such
+/// as its own database connection.
+pub struct Pool<T> {
+    sender: mpsc::Sender<Job<T>>,
+}
+
+/// Run jobs from `receiver` until the pool is dropped.
+///
+/// A panicking job must not take its worker down with it, or the pool would shrink until
+/// requests hang. The state is rebuilt after a panic since the job may have left it half-way.
+///
+/// A worker that cannot build its state leaves the jobs to the others. The last one left drops
+/// every job instead, which fails the requests they would have answered rather than leave them
+/// hanging.
+fn work<T, F: Fn() -> T>(name: &str, init: &F, receiver: &Mutex<mpsc::Receiver<Job<T>>>, alive: &AtomicUsize) {
+    let mut attempts = 0;
+    loop {
+        let mut state = match panic::catch_unwind(AssertUnwindSafe(init)) {
+            Ok(state) => state,
+            Err(_) if attempts + 1 < MAX_INIT_ATTEMPTS => {
+                attempts += 1;
+                warn!("{} failed to start, retrying", name);
+                thread::sleep(RETRY_DELAY);
+                continue;
+            },
+            Err(_) => {
+                if alive.fetch_sub(1, Ordering::SeqCst) > 1 {
+                    error!("{} failed to start {} times, giving up", name, MAX_INIT_ATTEMPTS);
+                    return;
+                }
+                error!("{} failed to start {} times and no worker is left, failing every request", name, MAX_INIT_ATTEMPTS);
+                while let Ok(job) = receiver.lock().unwrap().recv() {
+                    drop(job);
+                }
+                return;
+            },
+        };
+        attempts = 0;
+        loop {
+            let job = match receiver.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            };
+            if panic::catch_unwind(AssertUnwindSafe(|| job(&mut state))).is_err() {
+                warn!("job panicked on {}, restarting it", name);
+                break;
+            }
+        }
+    }
+}
+
+impl<T: 'static> Pool<T> {
+    /// Start `size` workers, each calling `init` on its own thread to build its state.
+    pub fn new<F>(size: usize, init: F) -> Self
+        where F: Fn() -> T + Send + Sync + 'static
+    {
+        let (sender, receiver) = mpsc::channel::<Job<T>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let init = Arc::new(init);
+        let alive = Arc::new(AtomicUsize::new(size.max(1)));
+        for i in 0..size.max(1) {
+            let receiver = receiver.clone();
+            let init = init.clone();
+            let alive = alive.clone();
+            let name = format!("worker-{}", i);
+            thread::Builder::new().name(name.clone()).spawn(move || work(&name, &*init, &receiver, &alive)).unwrap();
+        }
+        Pool {
+            sender,
+        }
+    }
+
+    /// Run `job` on the first idle worker.
+    pub fn execute<F: FnOnce(&mut T) + Send + 'static>(&self, job: F) {
+        self.sender.send(Box::new(job)).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::Pool;
+
+    #[test]
+    fn survives_panics() {
+        let started = Arc::new(AtomicUsize::new(0));
+        let counter = started.clone();
+        let pool = Pool::new(1, move || counter.fetch_add(1, Ordering::SeqCst));
+        pool.execute(|_| panic!("job failed"));
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move |&mut state| sender.send(state).unwrap());
+        assert_eq!(receiver.recv().unwrap(), 1);
+        assert_eq!(started.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn fails_jobs_without_workers() {
+        let pool: Pool<()> = Pool::new(1, || panic!("no database"));
+        let (sender, receiver) = mpsc::channel::<()>();
+        pool.execute(move |_| sender.send(()).unwrap());
+        assert!(receiver.recv().is_err());
+    }
+}
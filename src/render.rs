@@ -0,0 +1,264 @@
+extern crate image;
+extern crate md5;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use self::image::{DynamicImage, Rgb, RgbImage};
+use self::image::codecs::jpeg::JpegEncoder;
+
+use color::{Adjustments, RedEye};
+
+const JPEG_QUALITY: u8 = 90;
+
+/// Tells apart the temporary files of renders running at the same time.
+static RENDERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Crop box in pixels, with inclusive right and bottom edges as Shotwell stores them.
+#[derive(Debug)]
+struct Crop {
+    left: u32,
+    top: u32,
+    right: u32,
+    bottom: u32,
+}
+
+/// Edits parsed from `PhotoTable.transformations`.
+#[derive(Debug, Default)]
+pub struct Transformations {
+    crop: Option<Crop>,
+    straighten: f64,
+    redeye: Vec<RedEye>,
+    adjustments: Adjustments,
+}
+
+/// Parse GKeyFile contents into `group -> key -> value` maps, ignoring comments and malformed lines.
+fn parse_keyfile(text: &str) -> HashMap<String, HashMap<String, String>> {
+    let mut groups: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut group = String::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            group = line[1..line.len() - 1].to_owned();
+        } else if let Some(pos) = line.find('=') {
+            groups.entry(group.clone())
+                  .or_default()
+                  .insert(line[..pos].trim().to_owned(), line[pos + 1..].trim().to_owned());
+        }
+    }
+    groups
+}
+
+impl Transformations {
+    pub fn parse(keyfile: &str) -> Transformations {
+        let groups = parse_keyfile(keyfile);
+        let crop = groups.get("crop").and_then(|group| {
+            let value = |key: &str| group.get(key).and_then(|v| v.parse::<i64>().ok()).map(|v| v.max(0) as u32);
+            Some(Crop {
+                left: value("left")?,
+                top: value("top")?,
+                right: value("right")?,
+                bottom: value("bottom")?,
+            })
+        });
+        let straighten = groups.get("straighten")
+                               .and_then(|group| group.get("angle"))
+                               .and_then(|angle| angle.parse::<f64>().ok())
+                               .unwrap_or(0.0);
+        Transformations {
+            crop,
+            straighten,
+            redeye: RedEye::parse(groups.get("redeye")),
+            adjustments: Adjustments::parse(groups.get("adjustments")),
+        }
+    }
+}
+
+/// Rotate `image` by `degrees` clockwise around its centre, growing the canvas to fit the
+/// rotated corners, which are filled with black.
+fn straighten(image: &RgbImage, degrees: f64) -> RgbImage {
+    let (width, height) = (image.width() as f64, image.height() as f64);
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let new_width = (width * cos.abs() + height * sin.abs()).round().max(1.0);
+    let new_height = (width * sin.abs() + height * cos.abs()).round().max(1.0);
+    let mut result = RgbImage::new(new_width as u32, new_height as u32);
+    for (x, y, pixel) in result.enumerate_pixels_mut() {
+        let dx = x as f64 + 0.5 - new_width / 2.0;
+        let dy = y as f64 + 0.5 - new_height / 2.0;
+        let sx = dx * cos + dy * sin + width / 2.0 - 0.5;
+        let sy = -dx * sin + dy * cos + height / 2.0 - 0.5;
+        *pixel = sample_bilinear(image, sx, sy);
+    }
+    result
+}
+
+fn sample_bilinear(image: &RgbImage, x: f64, y: f64) -> Rgb<u8> {
+    let (width, height) = (image.width() as i64, image.height() as i64);
+    if x < -0.5 || y < -0.5 || x > width as f64 - 0.5 || y > height as f64 - 0.5 {
+        return Rgb([0, 0, 0]);
+    }
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let pixel = |px: i64, py: i64| image.get_pixel(px.max(0).min(width - 1) as u32, py.max(0).min(height - 1) as u32);
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let (p00, p10, p01, p11) = (pixel(x0, y0), pixel(x0 + 1, y0), pixel(x0, y0 + 1), pixel(x0 + 1, y0 + 1));
+    let mut result = [0u8; 3];
+    for (c, value) in result.iter_mut().enumerate() {
+        let top = p00[c] as f64 * (1.0 - fx) + p10[c] as f64 * fx;
+        let bottom = p01[c] as f64 * (1.0 - fx) + p11[c] as f64 * fx;
+        *value = (top * (1.0 - fy) + bottom * fy).round() as u8;
+    }
+    Rgb(result)
+}
+
+/// Apply EXIF-style `orientation` (1 to 8) as stored in `PhotoTable.orientation`.
+fn orient(image: RgbImage, orientation: i64) -> RgbImage {
+    use self::image::imageops::{flip_horizontal, flip_vertical, rotate90, rotate180, rotate270};
+    match orientation {
+        2 => flip_horizontal(&image),
+        3 => rotate180(&image),
+        4 => flip_vertical(&image),
+        5 => flip_horizontal(&rotate90(&image)),
+        6 => rotate90(&image),
+        7 => flip_horizontal(&rotate270(&image)),
+        8 => rotate270(&image),
+        _ => image,
+    }
+}
+
+/// Decode `source` and apply the edits in Shotwell's order: red-eye, straighten and crop work in
+/// raw pixel coordinates, colour adjustments follow and orientation is applied last.
+pub fn render(source: &Path, orientation: i64, transformations: &Transformations) -> io::Result<RgbImage> {
+    let mut image = image::open(source).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?.to_rgb8();
+    for redeye in &transformations.redeye {
+        redeye.apply(&mut image);
+    }
+    if transformations.straighten != 0.0 {
+        image = straighten(&image, transformations.straighten);
+    }
+    if let Some(ref crop) = transformations.crop {
+        let left = crop.left.min(image.width() - 1);
+        let top = crop.top.min(image.height() - 1);
+        let right = crop.right.max(left).min(image.width() - 1);
+        let bottom = crop.bottom.max(top).min(image.height() - 1);
+        image = DynamicImage::ImageRgb8(image).crop_imm(left, top, right - left + 1, bottom - top + 1).to_rgb8();
+    }
+    transformations.adjustments.apply(&mut image);
+    Ok(orient(image, orientation))
+}
+
+/// On-disk cache of rendered photos.
+///
+/// Each photo keeps its renderings in a directory named after its id, with entries named
+/// `<hash>.jpg`, where the hash covers the source file, its size and modification time, the
+/// orientation and the transformations, so any edit yields a new entry.
+pub struct RenderCache {
+    dir: PathBuf,
+}
+
+impl RenderCache {
+    pub fn new<T: Into<PathBuf>>(dir: T) -> Self {
+        RenderCache {
+            dir: dir.into(),
+        }
+    }
+
+    /// Path the rendered JPEG for `photo_id` is cached at, whether or not it was rendered yet.
+    pub fn entry(&self, photo_id: u64, source: &Path, orientation: i64, transformations: &str) -> io::Result<PathBuf> {
+        let metadata = fs::metadata(source)?;
+        let mut hasher = md5::Context::new();
+        hasher.consume(source.to_string_lossy().as_bytes());
+        hasher.consume(format!("\n{}\n{:?}\n{}\n", metadata.len(), metadata.modified().ok(), orientation).as_bytes());
+        hasher.consume(transformations.as_bytes());
+        Ok(self.dir.join(photo_id.to_string()).join(format!("{:x}.jpg", hasher.compute())))
+    }
+
+    /// Path of the rendered JPEG for `photo_id` if it is cached already.
+    pub fn cached(&self, photo_id: u64, source: &Path, orientation: i64, transformations: &str) -> Option<PathBuf> {
+        self.entry(photo_id, source, orientation, transformations).ok().filter(|path| path.exists())
+    }
+
+    /// Path of the rendered JPEG for `photo_id`, rendering it first if it is not cached yet.
+    pub fn get(&self, photo_id: u64, source: &Path, orientation: i64, transformations: &str) -> io::Result<PathBuf> {
+        let path = self.entry(photo_id, source, orientation, transformations)?;
+        if path.exists() {
+            return Ok(path);
+        }
+
+        // Workers render concurrently, so another may have just put the current rendering in
+        // place or removed a stale one already.
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir)?;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_name().to_str().is_some_and(|stale| stale.ends_with(".jpg") && entry.path() != path) {
+                debug!("removing stale rendered file {:?}", entry.path());
+                match fs::remove_file(entry.path()) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                    result => result?,
+                }
+            }
+        }
+
+        debug!("rendering photo id {} from {:?} into {:?}", photo_id, source, path);
+        let image = render(source, orientation, &Transformations::parse(transformations))?;
+        let tmp = dir.join(format!(".{}.{}.tmp", path.file_name().unwrap().to_string_lossy(), RENDERS.fetch_add(1, Ordering::Relaxed)));
+        {
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            JpegEncoder::new_with_quality(&mut writer, JPEG_QUALITY)
+                .encode_image(&image)
+                .map_err(io::Error::other)?;
+        }
+        fs::rename(&tmp, &path)?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Transformations;
+
+    #[test]
+    fn parse_empty() {
+        let transformations = Transformations::parse("");
+        assert!(transformations.crop.is_none());
+        assert_eq!(transformations.straighten, 0.0);
+        assert!(transformations.redeye.is_empty());
+    }
+
+    #[test]
+    fn parse_geometry() {
+        let transformations = Transformations::parse("\
+            # written by Shotwell\n\
+            [crop]\n\
+            left=10\n\
+            top=-4\n\
+            right=1033\n\
+            bottom = 767\n\
+            \n\
+            [straighten]\n\
+            angle=-2.5\n\
+            [redeye]\n\
+            num_points=2\n\
+            center0=(100, 120)\n\
+            radius0=8\n\
+            center1=(bogus)\n\
+            radius1=8\n");
+        let crop = transformations.crop.expect("crop");
+        assert_eq!((crop.left, crop.top, crop.right, crop.bottom), (10, 0, 1033, 767));
+        assert_eq!(transformations.straighten, -2.5);
+        assert_eq!(transformations.redeye.len(), 1);
+    }
+
+    #[test]
+    fn parse_incomplete() {
+        let transformations = Transformations::parse("[crop]\nleft=1\ntop=2\nright=x\nbottom=4\n[straighten]\nangle=steep\nno equals sign\n");
+        assert!(transformations.crop.is_none());
+        assert_eq!(transformations.straighten, 0.0);
+    }
+}
@@ -0,0 +1,403 @@
+extern crate sqlite;
+
+use sqlite_ex::TextField;
+
+/// SQL expression for the last `/`-separated component of `expr`: the file name of a path or
+/// the title of a hierarchical tag.
+fn basename(expr: &str) -> String {
+    format!("REPLACE({0}, RTRIM({0}, REPLACE({0}, '/', '')), '')", expr)
+}
+
+/// Escape `LIKE` wildcards in user text, to be used with `ESCAPE '\'`.
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+/// Translation of a saved search from `SavedSearchDBTable` and its criteria tables into a
+/// `media_query` filter.
+///
+/// Conditions follow Shotwell's `SearchCondition` classes and are combined according to the
+/// search operator (`ANY`, `ALL` or `NONE`). Text is bound as parameters numbered from `?2`.
+pub struct SavedSearch {
+    operator: String,
+    conditions: Vec<String>,
+    params: Vec<sqlite::Value>,
+}
+
+impl SavedSearch {
+    /// Load search `search_id`, or `None` when there is no such search.
+    pub fn load(conn: &sqlite::Connection, search_id: u64) -> Option<SavedSearch> {
+        let mut statement = conn.prepare("SELECT operator FROM SavedSearchDBTable WHERE id = ?").unwrap();
+        statement.bind(1, search_id as i64).unwrap();
+        let operator = match statement.next() {
+            Ok(sqlite::State::Row) => statement.read_text(0).unwrap_or_default(),
+            _ => return None,
+        };
+
+        let mut search = SavedSearch {
+            operator,
+            conditions: Vec::new(),
+            params: Vec::new(),
+        };
+        search.load_text(conn, search_id);
+        search.load_media_type(conn, search_id);
+        search.load_flagged(conn, search_id);
+        search.load_modified(conn, search_id);
+        search.load_rating(conn, search_id);
+        search.load_date(conn, search_id);
+        Some(search)
+    }
+
+    /// Filter matching the media found by the search.
+    pub fn filter(&self) -> String {
+        let any = if self.conditions.is_empty() { "0".to_owned() } else { self.conditions.join(" OR ") };
+        match &self.operator[..] {
+            "ALL" if !self.conditions.is_empty() => self.conditions.join(" AND "),
+            "ALL" => "1".to_owned(),
+            "NONE" => format!("NOT ({})", any),
+            _ => any,
+        }
+    }
+
+    /// Values of the parameters used by `filter`.
+    pub fn params(&self) -> &[sqlite::Value] {
+        &self.params
+    }
+
+    /// Rows of criteria table `table` belonging to `search_id`, or nothing when an older schema
+    /// lacks the table.
+    fn criteria<'c>(conn: &'c sqlite::Connection, table: &str, columns: &str, search_id: u64) -> Option<sqlite::Statement<'c>> {
+        let mut statement = conn.prepare(format!("SELECT {} FROM {} WHERE search_id = ? ORDER BY id ASC", columns, table)).ok()?;
+        statement.bind(1, search_id as i64).unwrap();
+        Some(statement)
+    }
+
+    /// Bind `value` as the next parameter and return its placeholder.
+    fn param(&mut self, value: sqlite::Value) -> String {
+        self.params.push(value);
+        format!("?{}", self.params.len() + 1)
+    }
+
+    fn load_text(&mut self, conn: &sqlite::Connection, search_id: u64) {
+        let mut statement = match SavedSearch::criteria(conn, "SavedSearchDBTable_Text", "search_type, context, text", search_id) {
+            Some(statement) => statement,
+            None => return,
+        };
+        while let Ok(sqlite::State::Row) = statement.next() {
+            let search_type = statement.read_text(0).unwrap_or_default();
+            let context = statement.read_text(1).unwrap_or_default();
+            let text = escape_like(&statement.read_text(2).unwrap_or_default());
+            let (pattern, negate) = match &context[..] {
+                "CONTAINS" => (Some(format!("%{}%", text)), false),
+                "DOES_NOT_CONTAIN" => (Some(format!("%{}%", text)), true),
+                "IS_EXACTLY" => (Some(text), false),
+                "STARTS_WITH" => (Some(format!("{}%", text)), false),
+                "ENDS_WITH" => (Some(format!("%{}", text)), false),
+                "IS_SET" => (None, false),
+                "IS_NOT_SET" => (None, true),
+                _ => {
+                    warn!("unknown text search context {:?} in saved search id {}", context, search_id);
+                    continue;
+                },
+            };
+            let predicate = match pattern {
+                Some(pattern) => {
+                    let param = self.param(sqlite::Value::String(pattern));
+                    format!("LIKE {} ESCAPE '\\'", param)
+                },
+                None => "!= ''".to_owned(),
+            };
+            let field = |column: &str| format!("COALESCE({}, '') {}", column, predicate);
+            let title = field("title");
+            let comment = field("comment");
+            let file_name = field(&basename("filename"));
+            let tag = format!("EXISTS (SELECT 1 FROM TagTable t WHERE ',' || t.photo_id_list || ',' LIKE '%,' || source_id || ',%' AND {})",
+                              field(&basename("t.name")));
+            let event = format!("EXISTS (SELECT 1 FROM EventTable e WHERE e.id = event_id AND {})", field("e.name"));
+            let condition = match &search_type[..] {
+                "ANY_TEXT" => format!("{} OR {} OR {} OR {} OR {}", title, comment, file_name, tag, event),
+                "TITLE" => title,
+                "COMMENT" => comment,
+                "FILE_NAME" => file_name,
+                "TAG" => tag,
+                "EVENT_NAME" => event,
+                _ => {
+                    warn!("unknown text search type {:?} in saved search id {}", search_type, search_id);
+                    continue;
+                },
+            };
+            self.conditions.push(if negate { format!("NOT ({})", condition) } else { format!("({})", condition) });
+        }
+    }
+
+    fn load_media_type(&mut self, conn: &sqlite::Connection, search_id: u64) {
+        let mut statement = match SavedSearch::criteria(conn, "SavedSearchDBTable_MediaType", "context, type", search_id) {
+            Some(statement) => statement,
+            None => return,
+        };
+        while let Ok(sqlite::State::Row) = statement.next() {
+            let condition = match &statement.read_text(1).unwrap_or_default()[..] {
+                "PHOTO_ALL" => "kind = 0",
+                "PHOTO_RAW" => "kind = 0 AND raw",
+                "VIDEO" => "kind = 1",
+                other => {
+                    warn!("unknown media type {:?} in saved search id {}", other, search_id);
+                    continue;
+                },
+            };
+            if statement.read_text(0).as_deref() == Some("IS_NOT") {
+                self.conditions.push(format!("NOT ({})", condition));
+            } else {
+                self.conditions.push(format!("({})", condition));
+            }
+        }
+    }
+
+    fn load_flagged(&mut self, conn: &sqlite::Connection, search_id: u64) {
+        let mut statement = match SavedSearch::criteria(conn, "SavedSearchDBTable_Flagged", "flag_state", search_id) {
+            Some(statement) => statement,
+            None => return,
+        };
+        while let Ok(sqlite::State::Row) = statement.next() {
+            match &statement.read_text(0).unwrap_or_default()[..] {
+                "FLAGGED" => self.conditions.push("flagged".to_owned()),
+                "UNFLAGGED" => self.conditions.push("NOT flagged".to_owned()),
+                other => warn!("unknown flag state {:?} in saved search id {}", other, search_id),
+            }
+        }
+    }
+
+    fn load_modified(&mut self, conn: &sqlite::Connection, search_id: u64) {
+        let mut statement = match SavedSearch::criteria(conn, "SavedSearchDBTable_Modified", "context, modified_state", search_id) {
+            Some(statement) => statement,
+            None => return,
+        };
+        while let Ok(sqlite::State::Row) = statement.next() {
+            let condition = match &statement.read_text(1).unwrap_or_default()[..] {
+                "MODIFIED" => "(internal_changes OR external_changes)",
+                "INTERNAL_CHANGES" => "internal_changes",
+                "EXTERNAL_CHANGES" => "external_changes",
+                other => {
+                    warn!("unknown modified state {:?} in saved search id {}", other, search_id);
+                    continue;
+                },
+            };
+            if statement.read_text(0).as_deref() == Some("HAS_NOT") {
+                self.conditions.push(format!("NOT {}", condition));
+            } else {
+                self.conditions.push(condition.to_owned());
+            }
+        }
+    }
+
+    fn load_rating(&mut self, conn: &sqlite::Connection, search_id: u64) {
+        let mut statement = match SavedSearch::criteria(conn, "SavedSearchDBTable_Rating", "rating, context", search_id) {
+            Some(statement) => statement,
+            None => return,
+        };
+        while let Ok(sqlite::State::Row) = statement.next() {
+            let rating = statement.read::<i64>(0).unwrap();
+            let operator = match &statement.read_text(1).unwrap_or_default()[..] {
+                "AND_HIGHER" => ">=",
+                "ONLY" => "=",
+                "AND_LOWER" => "<=",
+                other => {
+                    warn!("unknown rating context {:?} in saved search id {}", other, search_id);
+                    continue;
+                },
+            };
+            self.conditions.push(format!("rating {} {}", operator, rating));
+        }
+    }
+
+    fn load_date(&mut self, conn: &sqlite::Connection, search_id: u64) {
+        let mut statement = match SavedSearch::criteria(conn, "SavedSearchDBTable_Date", "context, date_one, date_two", search_id) {
+            Some(statement) => statement,
+            None => return,
+        };
+        let date = |expr: &str| format!("DATE({}, 'unixepoch', 'localtime')", expr);
+        while let Ok(sqlite::State::Row) = statement.next() {
+            let context = statement.read_text(0).unwrap_or_default();
+            let date_one = statement.read::<i64>(1).unwrap();
+            let date_two = statement.read::<i64>(2).unwrap();
+            let exposure = date("exposure_time");
+            let condition = match &context[..] {
+                "EXACT" => format!("exposure_time > 0 AND {} = {}", exposure, date(&date_one.to_string())),
+                "AFTER" => format!("exposure_time > 0 AND {} >= {}", exposure, date(&date_one.to_string())),
+                "BEFORE" => format!("exposure_time > 0 AND {} <= {}", exposure, date(&date_one.to_string())),
+                "BETWEEN" => format!("exposure_time > 0 AND {} BETWEEN {} AND {}", exposure, date(&date_one.to_string()), date(&date_two.to_string())),
+                "IS_NOT_SET" => "COALESCE(exposure_time, 0) <= 0".to_owned(),
+                _ => {
+                    warn!("unknown date context {:?} in saved search id {}", context, search_id);
+                    continue;
+                },
+            };
+            self.conditions.push(format!("({})", condition));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SavedSearch;
+
+    /// Library with the columns `media_query` offers to filters: a flagged JPEG photo titled
+    /// "Beach day" (id 1), an untitled, unflagged RAW photo (id 2) and an undated video titled
+    /// "Birthday" (id 3). Photos 1 and 3 are tagged `/Holidays/Beach`.
+    const LIBRARY: &str = "
+        CREATE TABLE media (id INTEGER, kind INTEGER, title TEXT, comment TEXT, filename TEXT, source_id TEXT, event_id INTEGER,
+                            flagged INTEGER, raw INTEGER, internal_changes INTEGER, external_changes INTEGER, rating INTEGER,
+                            exposure_time INTEGER);
+        INSERT INTO media VALUES (1, 0, 'Beach day', 'sunny', '/photos/IMG_0001.jpg', 'thumb0000000000000001', 1, 1, 0, 1, 0, 5, 1623758400);
+        INSERT INTO media VALUES (2, 0, NULL, NULL, '/photos/DSC_0002.NEF', 'thumb0000000000000002', 2, 0, 1, 0, 1, 2, 1609502400);
+        INSERT INTO media VALUES (3, 1, 'Birthday', NULL, '/videos/clip.mp4', 'video-0000000000000003', 1, 1, 0, 0, 0, 0, 0);
+        CREATE TABLE TagTable (id INTEGER PRIMARY KEY, name TEXT, photo_id_list TEXT);
+        INSERT INTO TagTable VALUES (1, '/Holidays', '');
+        INSERT INTO TagTable VALUES (2, '/Holidays/Beach', 'thumb0000000000000001,video-0000000000000003');
+        CREATE TABLE EventTable (id INTEGER PRIMARY KEY, name TEXT);
+        INSERT INTO EventTable VALUES (1, 'Summer');
+        INSERT INTO EventTable VALUES (2, 'New year');
+        CREATE TABLE SavedSearchDBTable (id INTEGER PRIMARY KEY, name TEXT, operator TEXT);
+        CREATE TABLE SavedSearchDBTable_Text (id INTEGER PRIMARY KEY, search_id INTEGER, search_type TEXT, context TEXT, text TEXT);
+        CREATE TABLE SavedSearchDBTable_MediaType (id INTEGER PRIMARY KEY, search_id INTEGER, context TEXT, type TEXT);
+        CREATE TABLE SavedSearchDBTable_Flagged (id INTEGER PRIMARY KEY, search_id INTEGER, flag_state TEXT);
+        CREATE TABLE SavedSearchDBTable_Rating (id INTEGER PRIMARY KEY, search_id INTEGER, rating INTEGER, context TEXT);
+        CREATE TABLE SavedSearchDBTable_Date (id INTEGER PRIMARY KEY, search_id INTEGER, context TEXT, date_one INTEGER, date_two INTEGER);
+    ";
+
+    /// Ids of the media matched by a search with `operator` and the criteria rows `criteria`,
+    /// given as `INSERT` statements for search id 1.
+    fn matches(operator: &str, criteria: &str) -> Vec<i64> {
+        let conn = sqlite::open(":memory:").unwrap();
+        conn.execute(LIBRARY).unwrap();
+        conn.execute(format!("INSERT INTO SavedSearchDBTable VALUES (1, 'search', '{}'); {}", operator, criteria)).unwrap();
+        let search = SavedSearch::load(&conn, 1).unwrap();
+        let mut statement = conn.prepare(format!("SELECT id FROM media WHERE {} ORDER BY id", search.filter())).unwrap();
+        for (i, param) in search.params().iter().enumerate() {
+            statement.bind(i + 2, param).unwrap();
+        }
+        let mut ids = Vec::new();
+        while let Ok(sqlite::State::Row) = statement.next() {
+            ids.push(statement.read::<i64>(0).unwrap());
+        }
+        ids
+    }
+
+    fn text(search_type: &str, context: &str, text: &str) -> Vec<i64> {
+        matches("ANY", &format!("INSERT INTO SavedSearchDBTable_Text (search_id, search_type, context, text) VALUES (1, '{}', '{}', '{}');",
+                                search_type, context, text))
+    }
+
+    #[test]
+    fn missing_search() {
+        let conn = sqlite::open(":memory:").unwrap();
+        conn.execute(LIBRARY).unwrap();
+        assert!(SavedSearch::load(&conn, 1).is_none());
+    }
+
+    #[test]
+    fn text_conditions() {
+        assert_eq!(text("TITLE", "CONTAINS", "day"), [1, 3]);
+        assert_eq!(text("TITLE", "DOES_NOT_CONTAIN", "beach"), [2, 3]);
+        assert_eq!(text("TITLE", "IS_NOT_SET", ""), [2]);
+        assert_eq!(text("COMMENT", "IS_SET", ""), [1]);
+        assert_eq!(text("FILE_NAME", "ENDS_WITH", ".nef"), [2]);
+        assert_eq!(text("FILE_NAME", "STARTS_WITH", "photos"), Vec::<i64>::new());
+        assert_eq!(text("TAG", "IS_EXACTLY", "Beach"), [1, 3]);
+        assert_eq!(text("TAG", "IS_EXACTLY", "Holidays"), Vec::<i64>::new());
+        assert_eq!(text("EVENT_NAME", "STARTS_WITH", "new"), [2]);
+        assert_eq!(text("ANY_TEXT", "CONTAINS", "sunny"), [1]);
+        assert_eq!(text("ANY_TEXT", "CONTAINS", "summer"), [1, 3]);
+        assert_eq!(text("TITLE", "CONTAINS", "_"), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn media_type_conditions() {
+        let media_type = |context: &str, media_type: &str| {
+            matches("ANY", &format!("INSERT INTO SavedSearchDBTable_MediaType (search_id, context, type) VALUES (1, '{}', '{}');",
+                                    context, media_type))
+        };
+        assert_eq!(media_type("IS", "PHOTO_ALL"), [1, 2]);
+        assert_eq!(media_type("IS", "PHOTO_RAW"), [2]);
+        assert_eq!(media_type("IS_NOT", "VIDEO"), [1, 2]);
+    }
+
+    #[test]
+    fn flagged_conditions() {
+        let flagged = |state: &str| matches("ANY", &format!("INSERT INTO SavedSearchDBTable_Flagged (search_id, flag_state) VALUES (1, '{}');", state));
+        assert_eq!(flagged("FLAGGED"), [1, 3]);
+        assert_eq!(flagged("UNFLAGGED"), [2]);
+    }
+
+    #[test]
+    fn rating_conditions() {
+        let rating = |rating: i64, context: &str| {
+            matches("ANY", &format!("INSERT INTO SavedSearchDBTable_Rating (search_id, rating, context) VALUES (1, {}, '{}');", rating, context))
+        };
+        assert_eq!(rating(3, "AND_HIGHER"), [1]);
+        assert_eq!(rating(2, "ONLY"), [2]);
+        assert_eq!(rating(2, "AND_LOWER"), [2, 3]);
+    }
+
+    #[test]
+    fn date_conditions() {
+        // Noon UTC on 2021-06-15, 2021-01-01 and 2021-03-01, the same local dates in any time zone
+        // within twelve hours of UTC.
+        let (june, january, march) = (1623758400, 1609502400, 1614600000);
+        let date = |context: &str, date_one: i64, date_two: i64| {
+            matches("ANY", &format!("INSERT INTO SavedSearchDBTable_Date (search_id, context, date_one, date_two) VALUES (1, '{}', {}, {});",
+                                    context, date_one, date_two))
+        };
+        assert_eq!(date("EXACT", june, 0), [1]);
+        assert_eq!(date("AFTER", march, 0), [1]);
+        assert_eq!(date("BEFORE", march, 0), [2]);
+        assert_eq!(date("BETWEEN", january, june), [1, 2]);
+        assert_eq!(date("IS_NOT_SET", 0, 0), [3]);
+    }
+
+    #[test]
+    fn combined_conditions() {
+        let criteria = "INSERT INTO SavedSearchDBTable_Flagged (search_id, flag_state) VALUES (1, 'FLAGGED');
+                        INSERT INTO SavedSearchDBTable_MediaType (search_id, context, type) VALUES (1, 'IS', 'PHOTO_ALL');";
+        assert_eq!(matches("ANY", criteria), [1, 2, 3]);
+        assert_eq!(matches("ALL", criteria), [1]);
+        assert_eq!(matches("NONE", "INSERT INTO SavedSearchDBTable_Rating (search_id, rating, context) VALUES (1, 2, 'AND_HIGHER');"), [3]);
+    }
+
+    fn search(operator: &str, conditions: &[&str]) -> SavedSearch {
+        SavedSearch {
+            operator: operator.to_owned(),
+            conditions: conditions.iter().map(|&condition| condition.to_owned()).collect(),
+            params: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn filter_without_conditions() {
+        assert_eq!(search("ANY", &[]).filter(), "0");
+        assert_eq!(search("ALL", &[]).filter(), "1");
+        assert_eq!(search("NONE", &[]).filter(), "NOT (0)");
+    }
+
+    #[test]
+    fn filter_with_conditions() {
+        let conditions = ["flagged", "rating >= 3"];
+        assert_eq!(search("ANY", &conditions).filter(), "flagged OR rating >= 3");
+        assert_eq!(search("ALL", &conditions).filter(), "flagged AND rating >= 3");
+        assert_eq!(search("NONE", &conditions).filter(), "NOT (flagged OR rating >= 3)");
+        assert_eq!(search("ALL", &["flagged"]).filter(), "flagged");
+    }
+
+    #[test]
+    fn unknown_operator_matches_any() {
+        assert_eq!(search("", &["flagged", "rating >= 3"]).filter(), "flagged OR rating >= 3");
+    }
+
+    #[test]
+    fn params_are_numbered_from_two() {
+        let mut search = search("ANY", &[]);
+        assert_eq!(search.param(sqlite::Value::String("%a%".to_owned())), "?2");
+        assert_eq!(search.param(sqlite::Value::String("%b%".to_owned())), "?3");
+        assert_eq!(search.params().len(), 2);
+    }
+}
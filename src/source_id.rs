@@ -0,0 +1,78 @@
+use std::fmt;
+
+const PHOTO_PREFIX: &str = "thumb";
+const VIDEO_PREFIX: &str = "video-";
+
+/// Reference to a media item as Shotwell stores it in `TagTable.photo_id_list`,
+/// `EventTable.primary_source_id` and thumbnail file names.
+///
+/// Photos are encoded as `thumb%016x` and videos as `video-%016x`, where the number is the row id
+/// in `PhotoTable` or `VideoTable` respectively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceId {
+    Photo(u64),
+    Video(u64),
+}
+
+impl SourceId {
+    pub fn parse(source: &str) -> Option<SourceId> {
+        let source = source.trim();
+        if let Some(id) = source.strip_prefix(VIDEO_PREFIX) {
+            u64::from_str_radix(id, 16).ok().map(SourceId::Video)
+        } else if let Some(id) = source.strip_prefix(PHOTO_PREFIX) {
+            u64::from_str_radix(id, 16).ok().map(SourceId::Photo)
+        } else {
+            None
+        }
+    }
+
+    /// Parse a comma-separated list of source ids, skipping empty and malformed items.
+    pub fn parse_list(list: &str) -> Vec<SourceId> {
+        list.split(',').filter_map(SourceId::parse).collect()
+    }
+}
+
+impl fmt::Display for SourceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SourceId::Photo(id) => write!(f, "{}{:016x}", PHOTO_PREFIX, id),
+            SourceId::Video(id) => write!(f, "{}{:016x}", VIDEO_PREFIX, id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SourceId;
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(SourceId::parse("thumb000000000000001a"), Some(SourceId::Photo(0x1a)));
+        assert_eq!(SourceId::parse("video-0000000000000003"), Some(SourceId::Video(3)));
+        for &source in &[SourceId::Photo(0x1a), SourceId::Video(3), SourceId::Photo(u64::MAX)] {
+            assert_eq!(SourceId::parse(&source.to_string()), Some(source));
+        }
+        assert_eq!(SourceId::Photo(0x1a).to_string(), "thumb000000000000001a");
+        assert_eq!(SourceId::Video(3).to_string(), "video-0000000000000003");
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(SourceId::parse(""), None);
+        assert_eq!(SourceId::parse("26"), None);
+        assert_eq!(SourceId::parse("thumb"), None);
+        assert_eq!(SourceId::parse("thumbxyz"), None);
+        assert_eq!(SourceId::parse("video-"), None);
+        assert_eq!(SourceId::parse("event-0000000000000001"), None);
+        assert_eq!(SourceId::parse("thumb10000000000000000"), None);
+    }
+
+    #[test]
+    fn list() {
+        assert_eq!(SourceId::parse_list("thumb0000000000000001,video-0000000000000002,"),
+                   vec![SourceId::Photo(1), SourceId::Video(2)]);
+        assert_eq!(SourceId::parse_list("thumb0000000000000001,,bogus, video-0000000000000002 ,thumb"),
+                   vec![SourceId::Photo(1), SourceId::Video(2)]);
+        assert_eq!(SourceId::parse_list(""), vec![]);
+    }
+}
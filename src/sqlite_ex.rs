@@ -1,4 +1,54 @@
 extern crate sqlite;
+extern crate sqlite3_sys as ffi;
+
+use std::ffi::{CStr, CString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+
+fn error(code: i32, message: &str) -> sqlite::Error {
+    sqlite::Error {
+        code: Some(code as isize),
+        message: Some(message.to_owned()),
+    }
+}
+
+/// Check that the database at `path` exists and opens in the requested mode, without creating it.
+fn probe(path: &Path, writable: bool) -> sqlite::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|_| error(ffi::SQLITE_CANTOPEN, "path contains a NUL byte"))?;
+    let flags = if writable { ffi::SQLITE_OPEN_READWRITE } else { ffi::SQLITE_OPEN_READONLY };
+    let mut raw = ptr::null_mut();
+    unsafe {
+        let code = ffi::sqlite3_open_v2(c_path.as_ptr(), &mut raw, flags, ptr::null());
+        let result = if code != ffi::SQLITE_OK {
+            let message = if raw.is_null() { "out of memory".into() } else { CStr::from_ptr(ffi::sqlite3_errmsg(raw)).to_string_lossy() };
+            Err(error(code, &message))
+        } else if writable && ffi::sqlite3_db_readonly(raw, b"main\0".as_ptr() as *const _) == 1 {
+            // SQLite falls back to read-only access for write-protected files.
+            Err(error(ffi::SQLITE_READONLY, "attempt to open a write-protected database for writing"))
+        } else {
+            Ok(())
+        };
+        ffi::sqlite3_close(raw);
+        result
+    }
+}
+
+/// Open the existing database at `path`, read-write only when `writable`.
+///
+/// `sqlite::open` always asks for read-write access and creates missing databases, and it is
+/// the only way to get a `sqlite::Connection`. So the database is first opened with
+/// `sqlite3_open_v2` in the requested mode and without `SQLITE_OPEN_CREATE`, which fails for
+/// missing or inaccessible files, and the connection handed out is limited with
+/// `PRAGMA query_only` unless `writable`.
+pub fn open_existing(path: &Path, writable: bool) -> sqlite::Result<sqlite::Connection> {
+    probe(path, writable)?;
+    let conn = sqlite::open(path)?;
+    if !writable {
+        conn.execute("PRAGMA query_only = ON")?;
+    }
+    Ok(conn)
+}
 
 pub trait TextField {
     fn read_text(&self, i: usize) -> Option<String>;
@@ -22,3 +72,27 @@ impl<'l> UnsignedField for sqlite::Statement<'l> {
         self.read::<i64>(i).map(|i| i as u64)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::open_existing;
+
+    #[test]
+    fn open_modes() {
+        let dir = std::env::temp_dir().join(format!("shotwellvfs-sqlite-ex-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("photo #1.db");
+        sqlite::open(&path).unwrap().execute("CREATE TABLE t (x)").unwrap();
+
+        assert!(open_existing(&path, false).unwrap().execute("INSERT INTO t VALUES (1)").is_err());
+        open_existing(&path, true).unwrap().execute("INSERT INTO t VALUES (1)").unwrap();
+
+        let missing = dir.join("missing.db");
+        assert!(open_existing(&missing, false).is_err());
+        assert!(open_existing(&missing, true).is_err());
+        assert!(!missing.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,23 @@
+use std::path::PathBuf;
+
+use source_id::SourceId;
+
+/// Shotwell's thumbnail cache, holding `thumbs128/` and `thumbs360/` directories of JPEG files
+/// named after the source id of each photo or video.
+#[derive(Clone)]
+pub struct ThumbnailCache {
+    dir: PathBuf,
+}
+
+impl ThumbnailCache {
+    pub fn new<T: Into<PathBuf>>(dir: T) -> Self {
+        ThumbnailCache {
+            dir: dir.into(),
+        }
+    }
+
+    /// Path of the cached thumbnail of `source` scaled to fit `size` pixels.
+    pub fn path(&self, size: u32, source: SourceId) -> PathBuf {
+        self.dir.join(format!("thumbs{}", size)).join(format!("{}.jpg", source))
+    }
+}
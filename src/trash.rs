@@ -0,0 +1,153 @@
+extern crate libc;
+extern crate time;
+
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+/// Percent-encode `path` for the `Path` key of a `.trashinfo` file, keeping `/` and the
+/// unreserved characters of RFC 2396.
+fn encode_path(path: &Path) -> String {
+    let mut encoded = String::new();
+    for &byte in path.as_os_str().as_bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'!' | b'~' | b'*' | b'\'' | b'(' | b')' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Topmost directory containing `path` on the same device: the mount point of its filesystem.
+fn top_dir(path: &Path, dev: u64) -> PathBuf {
+    let mut top = path.parent().unwrap_or(path).to_path_buf();
+    while let Some(parent) = top.parent() {
+        match fs::metadata(parent) {
+            Ok(metadata) if metadata.dev() == dev => top = parent.to_path_buf(),
+            _ => break,
+        }
+    }
+    top
+}
+
+/// Trash directories as described by the freedesktop.org Trash specification.
+///
+/// Files are moved into the home trash when it is on their filesystem, and into the trash at
+/// the top of their own filesystem otherwise, since trashing must never copy across devices.
+pub struct Trash {
+    home: PathBuf,
+}
+
+impl Trash {
+    pub fn new<T: Into<PathBuf>>(home: T) -> Self {
+        Trash {
+            home: home.into(),
+        }
+    }
+
+    /// Trash directory for files on `dev`, with the directory `Path` keys are relative to, or
+    /// `None` when paths are stored absolute.
+    fn trash_dir(&self, path: &Path, dev: u64) -> io::Result<(PathBuf, Option<PathBuf>)> {
+        let mut builder = DirBuilder::new();
+        builder.recursive(true).mode(0o700);
+        builder.create(&self.home)?;
+        if fs::metadata(&self.home)?.dev() == dev {
+            return Ok((self.home.clone(), None));
+        }
+
+        let top = top_dir(path, dev);
+        let uid = unsafe { libc::getuid() };
+        let shared = top.join(".Trash");
+        let dir = match fs::symlink_metadata(&shared) {
+            Ok(metadata) if metadata.is_dir() && metadata.mode() & libc::S_ISVTX != 0 => shared.join(uid.to_string()),
+            _ => top.join(format!(".Trash-{}", uid)),
+        };
+        builder.create(&dir)?;
+        Ok((dir, Some(top)))
+    }
+
+    /// Move `path` to the trash, returning its new location.
+    pub fn trash(&self, path: &Path) -> io::Result<PathBuf> {
+        let path = fs::canonicalize(path)?;
+        let dev = fs::symlink_metadata(&path)?.dev();
+        let (dir, top) = self.trash_dir(&path, dev)?;
+        let mut builder = DirBuilder::new();
+        builder.recursive(true).mode(0o700);
+        builder.create(dir.join("files"))?;
+        builder.create(dir.join("info"))?;
+
+        let stored = match top {
+            Some(ref top) => path.strip_prefix(top).unwrap_or(&path),
+            None => &path,
+        };
+        let deleted = time::now().strftime("%Y-%m-%dT%H:%M:%S").unwrap().to_string();
+        let info = format!("[Trash Info]\nPath={}\nDeletionDate={}\n", encode_path(stored), deleted);
+
+        // Claiming the name by creating its info file first keeps concurrent trashers apart.
+        let file_name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+        for attempt in 1.. {
+            let mut name = file_name.to_os_string();
+            if attempt > 1 {
+                name.push(format!(".{}", attempt));
+            }
+            let mut info_name = name.clone();
+            info_name.push(".trashinfo");
+            let info_path = dir.join("info").join(info_name);
+            let mut info_file = match OpenOptions::new().write(true).create_new(true).open(&info_path) {
+                Ok(file) => file,
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            };
+            let target = dir.join("files").join(name);
+            let moved = info_file.write_all(info.as_bytes()).and_then(|_| fs::rename(&path, &target));
+            if let Err(e) = moved {
+                let _ = fs::remove_file(&info_path);
+                return Err(e);
+            }
+            debug!("trashed {:?} as {:?}", path, target);
+            return Ok(target);
+        }
+        unreachable!()
+    }
+
+    /// Undo `trash`: move `trashed`, the location it returned, back to `original` and remove
+    /// its info file.
+    pub fn restore(&self, trashed: &Path, original: &Path) -> io::Result<()> {
+        let dir = trashed.parent().and_then(Path::parent).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not in a trash directory"))?;
+        let mut info_name = trashed.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?.to_os_string();
+        info_name.push(".trashinfo");
+        fs::rename(trashed, original)?;
+        fs::remove_file(dir.join("info").join(info_name))?;
+        debug!("restored {:?} from {:?}", original, trashed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::Trash;
+
+    #[test]
+    fn trash_and_restore() {
+        let dir = std::env::temp_dir().join(format!("shotwellvfs-trash-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let trash = Trash::new(dir.join("Trash"));
+        let original = dir.join("photo 1.jpg");
+        fs::write(&original, b"jpeg").unwrap();
+
+        let trashed = trash.trash(&original).unwrap();
+        assert_eq!(trashed, dir.join("Trash/files/photo 1.jpg"));
+        assert!(!original.exists());
+        let info = fs::read_to_string(dir.join("Trash/info/photo 1.jpg.trashinfo")).unwrap();
+        assert!(info.contains(&format!("Path={}/photo%201.jpg\n", fs::canonicalize(&dir).unwrap().display())));
+
+        trash.restore(&trashed, &original).unwrap();
+        assert_eq!(fs::read(&original).unwrap(), b"jpeg");
+        assert!(!dir.join("Trash/info/photo 1.jpg.trashinfo").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}